@@ -1,97 +1,679 @@
-#[derive(Debug)]
+use std::{collections::HashMap, fmt, mem, rc::Rc};
+
+use crate::{memory::{Heap, ObjRef}, scanner::Span, value::Value};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum OpCode {
     Constant = 0,
-    Add = 1,
-    Subtract = 2,
-    Multiply = 3,
-    Divide = 4,
-    Negate = 5,
-    Return = 6,
+    ConstantLong = 1,
+    Nil = 2,
+    True = 3,
+    False = 4,
+    Pop = 5,
+    GetLocal = 6,
+    SetLocal = 7,
+    GetGlobal = 8,
+    GetGlobalLong = 9,
+    DefineGlobal = 10,
+    DefineGlobalLong = 11,
+    SetGlobal = 12,
+    SetGlobalLong = 13,
+    GetUpvalue = 14,
+    SetUpvalue = 15,
+    GetProperty = 16,
+    GetPropertyLong = 17,
+    SetProperty = 18,
+    SetPropertyLong = 19,
+    GetSuper = 20,
+    GetSuperLong = 21,
+    Equal = 22,
+    Greater = 23,
+    Less = 24,
+    Add = 25,
+    Subtract = 26,
+    Multiply = 27,
+    Divide = 28,
+    Not = 29,
+    Negate = 30,
+    Print = 31,
+    Jump = 32,
+    JumpIfFalse = 33,
+    Loop = 34,
+    Call = 35,
+    Invoke = 36,
+    InvokeLong = 37,
+    SuperInvoke = 38,
+    SuperInvokeLong = 39,
+    Closure = 40,
+    ClosureLong = 41,
+    CloseUpvalue = 42,
+    Return = 43,
+    Class = 44,
+    ClassLong = 45,
+    Inherit = 46,
+    Method = 47,
+    MethodLong = 48,
 }
 
-impl From<u8> for OpCode {
-    fn from(opcode: u8) -> Self {
-        match opcode {
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(opcode: u8) -> Result<Self, Self::Error> {
+        Ok(match opcode {
             0 => OpCode::Constant,
-            1 => OpCode::Add,
-            2 => OpCode::Subtract,
-            3 => OpCode::Multiply,
-            4 => OpCode::Divide,
-            5 => OpCode::Negate,
-            6 => OpCode::Return,
-            _ => panic!("Unknown opcode: {opcode}"),
-        }
+            1 => OpCode::ConstantLong,
+            2 => OpCode::Nil,
+            3 => OpCode::True,
+            4 => OpCode::False,
+            5 => OpCode::Pop,
+            6 => OpCode::GetLocal,
+            7 => OpCode::SetLocal,
+            8 => OpCode::GetGlobal,
+            9 => OpCode::GetGlobalLong,
+            10 => OpCode::DefineGlobal,
+            11 => OpCode::DefineGlobalLong,
+            12 => OpCode::SetGlobal,
+            13 => OpCode::SetGlobalLong,
+            14 => OpCode::GetUpvalue,
+            15 => OpCode::SetUpvalue,
+            16 => OpCode::GetProperty,
+            17 => OpCode::GetPropertyLong,
+            18 => OpCode::SetProperty,
+            19 => OpCode::SetPropertyLong,
+            20 => OpCode::GetSuper,
+            21 => OpCode::GetSuperLong,
+            22 => OpCode::Equal,
+            23 => OpCode::Greater,
+            24 => OpCode::Less,
+            25 => OpCode::Add,
+            26 => OpCode::Subtract,
+            27 => OpCode::Multiply,
+            28 => OpCode::Divide,
+            29 => OpCode::Not,
+            30 => OpCode::Negate,
+            31 => OpCode::Print,
+            32 => OpCode::Jump,
+            33 => OpCode::JumpIfFalse,
+            34 => OpCode::Loop,
+            35 => OpCode::Call,
+            36 => OpCode::Invoke,
+            37 => OpCode::InvokeLong,
+            38 => OpCode::SuperInvoke,
+            39 => OpCode::SuperInvokeLong,
+            40 => OpCode::Closure,
+            41 => OpCode::ClosureLong,
+            42 => OpCode::CloseUpvalue,
+            43 => OpCode::Return,
+            44 => OpCode::Class,
+            45 => OpCode::ClassLong,
+            46 => OpCode::Inherit,
+            47 => OpCode::Method,
+            48 => OpCode::MethodLong,
+            _ => return Err(opcode),
+        })
+    }
+}
+
+impl OpCode {
+    // Whether the opcode's constant index takes three bytes instead of one. Every opcode that
+    // names a constant has a long form so a chunk can use all MAX_CONSTANTS of them.
+    pub fn has_long_constant(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::GetGlobalLong
+                | OpCode::DefineGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::GetSuperLong
+                | OpCode::InvokeLong
+                | OpCode::SuperInvokeLong
+                | OpCode::ClosureLong
+                | OpCode::ClassLong
+                | OpCode::MethodLong
+        )
     }
 }
 
-pub type Value = f64;
+// The most constants a chunk can hold, the number a three-byte operand can index.
+pub const MAX_CONSTANTS: usize = 1 << 24;
 
 pub struct Chunk {
     pub code: Vec<u8>,
-    lines: Vec<usize>,
+    // One entry per run of bytes compiled from the same source line, in code order.
+    lines: Vec<LineStart>,
+    // One entry per run of bytes compiled from the same token, in code order. Each is stored as
+    // three LEB128 numbers relative to the run before it, so most take three bytes: the bytes of
+    // code since that run started, how far the source start moved (zigzag-encoded, since operators
+    // are emitted after their right operand), and the length of the source range.
+    spans: Vec<u8>,
+    // The last run written, which the next one is encoded relative to.
+    last_span: SpanStart,
     pub constants: Vec<Value>,
+    // Index of each constant, so adding the same value twice reuses its slot.
+    constant_indices: HashMap<ConstantKey, usize>,
+    // The source the chunk was compiled from, shared with every other chunk compiled from it.
+    source: Rc<str>,
+}
+
+struct LineStart {
+    offset: usize,
+    line: usize,
+}
+
+// Lines are kept in their own table so that looking one up doesn't need the source, which makes
+// the byte range all this has to store.
+#[derive(Clone, Copy, Default)]
+struct SpanStart {
+    offset: usize,
+    start: usize,
+    end: usize,
+}
+
+// Values compared the way constants are deduplicated: numbers by their bits, so 0 and -0 stay
+// apart, and objects by reference, which is enough because strings are interned.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    Obj(ObjRef),
+}
+
+impl From<Value> for ConstantKey {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Nil => ConstantKey::Nil,
+            Value::Bool(value) => ConstantKey::Bool(value),
+            Value::Number(value) => ConstantKey::Number(value.to_bits()),
+            Value::Obj(obj) => ConstantKey::Obj(obj),
+        }
+    }
 }
 
 impl Chunk {
-    pub fn new() -> Self {
+    pub fn new(source: Rc<str>) -> Self {
         Self {
             code: Vec::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
+            last_span: SpanStart::default(),
             constants: Vec::new(),
+            constant_indices: HashMap::new(),
+            source,
         }
     }
 
-    pub fn add_constant(&mut self, value: Value) -> usize {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // The index of the constant, or None if it's new and the chunk already holds MAX_CONSTANTS.
+    pub fn add_constant(&mut self, value: Value) -> Option<usize> {
+        let key = value.into();
+        if let Some(&index) = self.constant_indices.get(&key) {
+            return Some(index);
+        }
+        if self.constants.len() == MAX_CONSTANTS {
+            return None;
+        }
         self.constants.push(value);
-        self.constants.len() - 1
+        self.constant_indices.insert(key, self.constants.len() - 1);
+        Some(self.constants.len() - 1)
     }
 
-    pub fn write_chunk(&mut self, byte: u8, line: usize) {
+    pub fn write_chunk(&mut self, byte: u8, span: Span) {
+        let offset = self.code.len();
+        if self.lines.last().is_none_or(|last| last.line != span.line) {
+            self.lines.push(LineStart { offset, line: span.line });
+        }
+        let last = self.last_span;
+        if self.spans.is_empty() || (last.start, last.end) != (span.start, span.end) {
+            write_leb128(&mut self.spans, (offset - last.offset) as u64);
+            write_leb128(&mut self.spans, zigzag(span.start as i64 - last.start as i64));
+            write_leb128(&mut self.spans, (span.end - span.start) as u64);
+            self.last_span = SpanStart { offset, start: span.start, end: span.end };
+        }
         self.code.push(byte);
-        self.lines.push(line);
     }
 
-    // TODO: Call this from behind a debug flag, somehow.
-    //       https://craftinginterpreters.com/compiling-expressions.html#dumping-chunks
-    fn disassemble_chunk(&self, name: &str) {
-        println!("== {} ==", name);
+    // The source line the byte at the offset was compiled from.
+    pub fn line_at(&self, offset: usize) -> usize {
+        let run = self.lines.partition_point(|start| start.offset <= offset);
+        self.lines[run - 1].line
+    }
+
+    // The source the byte at the offset was compiled from. This decodes the runs from the start,
+    // which is fine for reporting errors but too slow for anything done per instruction.
+    pub fn span_at(&self, offset: usize) -> Span {
+        let mut bytes = self.spans.iter().copied();
+        let mut next = SpanStart::default();
+        let mut run = next;
+        while let Some(advanced) = read_leb128(&mut bytes) {
+            let moved = read_leb128(&mut bytes).unwrap();
+            let len = read_leb128(&mut bytes).unwrap();
+            next.offset += advanced as usize;
+            if next.offset > offset {
+                break;
+            }
+            next.start = (next.start as i64 + unzigzag(moved)) as usize;
+            next.end = next.start + len as usize;
+            run = next;
+        }
 
+        let line_start = self.source.as_bytes()[..run.start].iter().rposition(|&c| c == b'\n').map_or(0, |newline| newline + 1);
+        Span {
+            start: run.start,
+            end: run.end,
+            line: self.line_at(offset),
+            column: run.start - line_start + 1,
+        }
+    }
+
+    // An estimate of the memory owned by the chunk.
+    pub fn size(&self) -> usize {
+        self.code.capacity()
+            + self.lines.capacity() * mem::size_of::<LineStart>()
+            + self.spans.capacity()
+            + self.constants.capacity() * mem::size_of::<Value>()
+    }
+
+    pub fn disassemble(&self, heap: &Heap) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            let instruction = self.disassemble_instruction(heap, offset);
+            offset += instruction.len;
+            instructions.push(instruction);
+        }
+        instructions
+    }
+
+    pub fn disassemble_instruction(&self, heap: &Heap, offset: usize) -> Instruction {
+        let mut instruction = Instruction {
+            offset,
+            len: 1,
+            line: self.line_at(offset),
+            opcode: None,
+            operands: Vec::new(),
+            text: String::new(),
+        };
+
+        let Ok(opcode) = OpCode::try_from(self.code[offset]) else {
+            instruction.text = format!("Unknown opcode {}", self.code[offset]);
+            return instruction;
+        };
+        instruction.opcode = Some(opcode);
+
+        match opcode {
+            OpCode::Constant => self.constant_instruction("OP_CONSTANT", heap, &mut instruction),
+            OpCode::ConstantLong => self.constant_instruction("OP_CONSTANT_LONG", heap, &mut instruction),
+            OpCode::Nil => self.simple_instruction("OP_NIL", &mut instruction),
+            OpCode::True => self.simple_instruction("OP_TRUE", &mut instruction),
+            OpCode::False => self.simple_instruction("OP_FALSE", &mut instruction),
+            OpCode::Pop => self.simple_instruction("OP_POP", &mut instruction),
+            OpCode::GetLocal => self.byte_instruction("OP_GET_LOCAL", &mut instruction),
+            OpCode::SetLocal => self.byte_instruction("OP_SET_LOCAL", &mut instruction),
+            OpCode::GetGlobal => self.constant_instruction("OP_GET_GLOBAL", heap, &mut instruction),
+            OpCode::GetGlobalLong => self.constant_instruction("OP_GET_GLOBAL_LONG", heap, &mut instruction),
+            OpCode::DefineGlobal => self.constant_instruction("OP_DEFINE_GLOBAL", heap, &mut instruction),
+            OpCode::DefineGlobalLong => self.constant_instruction("OP_DEFINE_GLOBAL_LONG", heap, &mut instruction),
+            OpCode::SetGlobal => self.constant_instruction("OP_SET_GLOBAL", heap, &mut instruction),
+            OpCode::SetGlobalLong => self.constant_instruction("OP_SET_GLOBAL_LONG", heap, &mut instruction),
+            OpCode::GetUpvalue => self.byte_instruction("OP_GET_UPVALUE", &mut instruction),
+            OpCode::SetUpvalue => self.byte_instruction("OP_SET_UPVALUE", &mut instruction),
+            OpCode::GetProperty => self.constant_instruction("OP_GET_PROPERTY", heap, &mut instruction),
+            OpCode::GetPropertyLong => self.constant_instruction("OP_GET_PROPERTY_LONG", heap, &mut instruction),
+            OpCode::SetProperty => self.constant_instruction("OP_SET_PROPERTY", heap, &mut instruction),
+            OpCode::SetPropertyLong => self.constant_instruction("OP_SET_PROPERTY_LONG", heap, &mut instruction),
+            OpCode::GetSuper => self.constant_instruction("OP_GET_SUPER", heap, &mut instruction),
+            OpCode::GetSuperLong => self.constant_instruction("OP_GET_SUPER_LONG", heap, &mut instruction),
+            OpCode::Equal => self.simple_instruction("OP_EQUAL", &mut instruction),
+            OpCode::Greater => self.simple_instruction("OP_GREATER", &mut instruction),
+            OpCode::Less => self.simple_instruction("OP_LESS", &mut instruction),
+            OpCode::Add => self.simple_instruction("OP_ADD", &mut instruction),
+            OpCode::Subtract => self.simple_instruction("OP_SUBTRACT", &mut instruction),
+            OpCode::Multiply => self.simple_instruction("OP_MULTIPLY", &mut instruction),
+            OpCode::Divide => self.simple_instruction("OP_DIVIDE", &mut instruction),
+            OpCode::Not => self.simple_instruction("OP_NOT", &mut instruction),
+            OpCode::Negate => self.simple_instruction("OP_NEGATE", &mut instruction),
+            OpCode::Print => self.simple_instruction("OP_PRINT", &mut instruction),
+            OpCode::Jump => self.jump_instruction("OP_JUMP", 1, &mut instruction),
+            OpCode::JumpIfFalse => self.jump_instruction("OP_JUMP_IF_FALSE", 1, &mut instruction),
+            OpCode::Loop => self.jump_instruction("OP_LOOP", -1, &mut instruction),
+            OpCode::Call => self.byte_instruction("OP_CALL", &mut instruction),
+            OpCode::Invoke => self.invoke_instruction("OP_INVOKE", heap, &mut instruction),
+            OpCode::InvokeLong => self.invoke_instruction("OP_INVOKE_LONG", heap, &mut instruction),
+            OpCode::SuperInvoke => self.invoke_instruction("OP_SUPER_INVOKE", heap, &mut instruction),
+            OpCode::SuperInvokeLong => self.invoke_instruction("OP_SUPER_INVOKE_LONG", heap, &mut instruction),
+            OpCode::Closure => self.closure_instruction("OP_CLOSURE", heap, &mut instruction),
+            OpCode::ClosureLong => self.closure_instruction("OP_CLOSURE_LONG", heap, &mut instruction),
+            OpCode::CloseUpvalue => self.simple_instruction("OP_CLOSE_UPVALUE", &mut instruction),
+            OpCode::Return => self.simple_instruction("OP_RETURN", &mut instruction),
+            OpCode::Class => self.constant_instruction("OP_CLASS", heap, &mut instruction),
+            OpCode::ClassLong => self.constant_instruction("OP_CLASS_LONG", heap, &mut instruction),
+            OpCode::Inherit => self.simple_instruction("OP_INHERIT", &mut instruction),
+            OpCode::Method => self.constant_instruction("OP_METHOD", heap, &mut instruction),
+            OpCode::MethodLong => self.constant_instruction("OP_METHOD_LONG", heap, &mut instruction),
+        }
+
+        instruction
+    }
+
+    pub fn display<'a>(&'a self, heap: &'a Heap) -> ChunkDisplay<'a> {
+        ChunkDisplay { chunk: self, heap }
+    }
+
+    fn constant_instruction(&self, name: &str, heap: &Heap, instruction: &mut Instruction) {
+        let constant_index = self.read_constant_index(instruction);
+        let constant_value = self.constants[constant_index];
+        instruction.operands.push(Operand::Constant(constant_index));
+        instruction.text = format!("{} {:4} '{}'", name, constant_index, constant_value.display(heap));
+    }
+
+    fn invoke_instruction(&self, name: &str, heap: &Heap, instruction: &mut Instruction) {
+        let constant_index = self.read_constant_index(instruction);
+        let arg_count = self.code[instruction.offset + instruction.len];
+        let constant_value = self.constants[constant_index];
+        instruction.len += 1;
+        instruction.operands.push(Operand::Constant(constant_index));
+        instruction.operands.push(Operand::Byte(arg_count));
+        instruction.text = format!("{} ({} args) {:4} '{}'", name, arg_count, constant_index, constant_value.display(heap));
+    }
+
+    fn closure_instruction(&self, name: &str, heap: &Heap, instruction: &mut Instruction) {
+        let constant_index = self.read_constant_index(instruction);
+        let constant_value = self.constants[constant_index];
+        instruction.operands.push(Operand::Constant(constant_index));
+        instruction.text = format!("{} {:4} {}", name, constant_index, constant_value.display(heap));
+
+        let Value::Obj(function) = constant_value else {
+            return;
+        };
+        let upvalue_count = heap.as_function(function).map_or(0, |function| function.upvalue_count);
+        for _ in 0..upvalue_count {
+            let offset = instruction.offset + instruction.len;
+            instruction.operands.push(Operand::Upvalue {
+                is_local: self.code[offset] == 1,
+                index: self.code[offset + 1],
+            });
+            instruction.len += 2;
         }
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04} ", offset);
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
+    // Reads the constant index that follows the opcode, in whichever width the opcode uses, and
+    // extends the instruction past it.
+    fn read_constant_index(&self, instruction: &mut Instruction) -> usize {
+        let offset = instruction.offset;
+        if instruction.opcode.is_some_and(OpCode::has_long_constant) {
+            instruction.len = 4;
+            u32::from_be_bytes([0, self.code[offset + 1], self.code[offset + 2], self.code[offset + 3]]) as usize
         } else {
-            print!("{:4} ", self.lines[offset]);
+            instruction.len = 2;
+            self.code[offset + 1] as usize
         }
+    }
 
-        let opcode: OpCode = self.code[offset].into();
-        match opcode {
-            OpCode::Constant => self.constant_instruction("OP_CONSTANT", offset),
-            OpCode::Add => self.simple_instruction("OP_ADD", offset),
-            OpCode::Subtract => self.simple_instruction("OP_SUBTRACT", offset),
-            OpCode::Multiply => self.simple_instruction("OP_MULTIPLY", offset),
-            OpCode::Divide => self.simple_instruction("OP_DIVIDE", offset),
-            OpCode::Negate => self.simple_instruction("OP_NEGATE", offset),
-            OpCode::Return => self.simple_instruction("OP_RETURN", offset),
+    fn byte_instruction(&self, name: &str, instruction: &mut Instruction) {
+        let slot = self.code[instruction.offset + 1];
+        instruction.len = 2;
+        instruction.operands.push(Operand::Byte(slot));
+        instruction.text = format!("{} {:4}", name, slot);
+    }
+
+    fn jump_instruction(&self, name: &str, sign: isize, instruction: &mut Instruction) {
+        let offset = instruction.offset;
+        let jump = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+        let target = offset as isize + 3 + sign * jump as isize;
+        instruction.len = 3;
+        instruction.operands.push(Operand::Jump(target));
+        instruction.text = format!("{} {:4} -> {}", name, offset, target);
+    }
+
+    fn simple_instruction(&self, name: &str, instruction: &mut Instruction) {
+        instruction.text = name.to_string();
+    }
+}
+
+// Maps signed numbers to unsigned ones with small magnitudes staying small: 0, -1, 1, -2, ...
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn write_leb128(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_leb128(bytes: &mut impl Iterator<Item = u8>) -> Option<u64> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes.next()?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
         }
+        shift += 7;
     }
+}
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant_index = self.code[offset + 1];
-        let constant_value = self.constants[constant_index as usize];
-        println!("{} {:4} '{}'", name, constant_index, constant_value);
-        offset + 2
+pub struct Instruction {
+    pub offset: usize,
+    // Number of bytes taken by the opcode and its operands.
+    pub len: usize,
+    pub line: usize,
+    // None for a byte that isn't a known opcode.
+    pub opcode: Option<OpCode>,
+    pub operands: Vec<Operand>,
+    // The opcode and its operands as they appear in a listing.
+    pub text: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    // A stack slot, upvalue index or argument count.
+    Byte(u8),
+    Constant(usize),
+    // The offset execution continues at when the jump is taken.
+    Jump(isize),
+    // One of the variables a closure captures.
+    Upvalue { is_local: bool, index: u8 },
+}
+
+impl Instruction {
+    // Writes the instruction as a listing line, replacing the line number with "|" when it's the
+    // same as the previous instruction's.
+    fn fmt_listing(&self, f: &mut fmt::Formatter, show_line: bool) -> fmt::Result {
+        if show_line {
+            write!(f, "{:04} {:4} {}", self.offset, self.line, self.text)?;
+        } else {
+            write!(f, "{:04}    | {}", self.offset, self.text)?;
+        }
+
+        let mut offset = self.offset + if self.opcode.is_some_and(OpCode::has_long_constant) { 4 } else { 2 };
+        for operand in &self.operands {
+            if let Operand::Upvalue { is_local, index } = operand {
+                write!(f, "\n{:04}    |                     {} {}", offset, if *is_local { "local" } else { "upvalue" }, index)?;
+                offset += 2;
+            }
+        }
+        Ok(())
     }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_listing(f, true)
+    }
+}
+
+// Constants can only be shown with the heap they live on, so chunks are displayed through this.
+pub struct ChunkDisplay<'a> {
+    chunk: &'a Chunk,
+    heap: &'a Heap,
+}
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{}", name);
-        offset + 1
+impl fmt::Display for ChunkDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut previous_line = None;
+        for instruction in self.chunk.disassemble(self.heap) {
+            instruction.fmt_listing(f, previous_line != Some(instruction.line))?;
+            writeln!(f)?;
+            previous_line = Some(instruction.line);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+
+    fn span(line: usize) -> Span {
+        Span { start: 0, end: 0, line, column: 1 }
+    }
+
+    #[test]
+    fn listing_shows_jumps_upvalues_and_long_operands() {
+        // The first line fills the constant pool, so the function's name and constant need the
+        // long forms.
+        let padding: String = (0..256).map(|i| format!("{};", i)).collect();
+        let source = format!(
+            "{}
+fun outer() {{
+  var x = 1;
+  fun inner() {{ return x; }}
+  return inner;
+}}
+if (true) print 1; else print 2;",
+            padding
+        );
+
+        let mut heap = Heap::new();
+        let Ok(script) = Compiler::compile(Rc::from(source), &mut heap, Vec::new()) else {
+            panic!("The script should compile.");
+        };
+        let chunk = &heap.as_function(script).unwrap().chunk;
+        let listing = chunk.display(&heap).to_string();
+        // Skip the OP_CONSTANT and OP_POP for each padding number.
+        let listing: Vec<&str> = listing.lines().skip(2 * 256).collect();
+        assert_eq!(
+            listing,
+            [
+                "0768    6 OP_CLOSURE_LONG  257 <fn outer>",
+                "0772    | OP_DEFINE_GLOBAL_LONG  256 'outer'",
+                "0776    7 OP_TRUE",
+                "0777    | OP_JUMP_IF_FALSE  777 -> 787",
+                "0780    | OP_POP",
+                "0781    | OP_CONSTANT    1 '1'",
+                "0783    | OP_PRINT",
+                "0784    | OP_JUMP  784 -> 791",
+                "0787    | OP_POP",
+                "0788    | OP_CONSTANT    2 '2'",
+                "0790    | OP_PRINT",
+                "0791    | OP_NIL",
+                "0792    | OP_RETURN",
+            ]
+        );
+
+        let Value::Obj(outer) = chunk.constants[257] else {
+            panic!("Constant 257 should be outer.");
+        };
+        let outer = &heap.as_function(outer).unwrap().chunk;
+        assert_eq!(
+            outer.display(&heap).to_string(),
+            "\
+0000    3 OP_CONSTANT    0 '1'
+0002    4 OP_CLOSURE    1 <fn inner>
+0004    |                     local 1
+0006    5 OP_GET_LOCAL    2
+0008    | OP_RETURN
+0009    6 OP_NIL
+0010    | OP_RETURN
+"
+        );
+
+        let closure = outer.disassemble_instruction(&heap, 2);
+        assert_eq!(closure.len, 4);
+        assert_eq!(closure.operands, [Operand::Constant(1), Operand::Upvalue { is_local: true, index: 1 }]);
+        let jump = chunk.disassemble_instruction(&heap, 777);
+        assert_eq!(jump.operands, [Operand::Jump(787)]);
+    }
+
+    #[test]
+    fn line_at_covers_each_run_from_start_to_end() {
+        let mut chunk = Chunk::new(Rc::from(""));
+        for line in [1, 1, 1, 2, 5, 5, 5, 5, 6] {
+            chunk.write_chunk(OpCode::Nil as u8, span(line));
+        }
+
+        assert_eq!(chunk.lines.len(), 4);
+        for (offset, line) in [(0, 1), (2, 1), (3, 2), (4, 5), (7, 5), (8, 6)] {
+            assert_eq!(chunk.line_at(offset), line, "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn span_at_covers_each_run_from_start_to_end() {
+        let source = "a + bb * ccc\n  -d";
+        let mut chunk = Chunk::new(Rc::from(source));
+        let spans = [(0, 1, 1), (4, 6, 1), (9, 12, 1), (7, 8, 1), (2, 3, 1), (16, 17, 2), (15, 16, 2)];
+        // Two bytes per token, so every run has a distinct first and last byte.
+        for (start, end, line) in spans {
+            for _ in 0..2 {
+                chunk.write_chunk(OpCode::Nil as u8, Span { start, end, line, column: 0 });
+            }
+        }
+
+        for (i, (start, end, line)) in spans.into_iter().enumerate() {
+            for offset in [i * 2, i * 2 + 1] {
+                let span = chunk.span_at(offset);
+                assert_eq!((span.start, span.end, span.line), (start, end, line), "offset {}", offset);
+            }
+        }
+        assert_eq!(chunk.span_at(1).column, 1);
+        assert_eq!(chunk.span_at(6).column, 8);
+        assert_eq!(chunk.span_at(13).column, 3);
+    }
+
+    #[test]
+    fn line_and_span_tables_stay_small_for_long_scripts() {
+        let lines = 5000;
+        let source: String = std::iter::once("var sum = 0;".to_string())
+            .chain((1..lines).map(|i| format!("sum = sum + {};", i)))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut heap = Heap::new();
+        let Ok(function) = Compiler::compile(Rc::from(source), &mut heap, Vec::new()) else {
+            panic!("The script should compile.");
+        };
+        let chunk = &heap.as_function(function).unwrap().chunk;
+
+        // One run per source line, against the one usize per byte a flat table would take. The
+        // spans, one per token, still leave both tables together well under the flat one.
+        assert_eq!(chunk.lines.len(), lines);
+        let line_table = chunk.lines.len() * mem::size_of::<LineStart>();
+        let flat = chunk.code.len() * mem::size_of::<usize>();
+        assert!(line_table * 4 < flat, "{} bytes of line table for {} bytes of code", line_table, chunk.code.len());
+        let tables = line_table + chunk.spans.len();
+        assert!(tables * 2 < flat, "{} bytes of line and span tables for {} bytes of code", tables, chunk.code.len());
+
+        for (i, run) in chunk.lines.iter().enumerate() {
+            let end = chunk.lines.get(i + 1).map_or(chunk.code.len(), |next| next.offset) - 1;
+            assert_eq!(chunk.line_at(run.offset), i + 1);
+            assert_eq!(chunk.line_at(end), i + 1);
+        }
     }
 }
@@ -1,19 +1,63 @@
-use std::mem;
+use std::{mem, rc::Rc};
 
-use crate::{scanner::*, chunk::{Chunk, OpCode, Value}};
+use crate::{scanner::*, chunk::{Chunk, OpCode}, diagnostic::{Code, Diagnostic, Severity}, memory::{Heap, ObjRef}, object::ObjFunction, value::Value};
+
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
 
 pub struct Compiler {
-    source: String,
-    chunk: Chunk,
+    source: Rc<str>,
+    heap: Heap,
     scanner: Scanner,
     parser: Parser,
+    // Every error found so far. The compile fails if there are any.
+    diagnostics: Vec<Diagnostic>,
+    // One entry per function being compiled, innermost last.
+    compilers: Vec<FunctionCompiler>,
+    // One entry per class body being compiled, innermost last.
+    classes: Vec<ClassCompiler>,
+    // Objects the VM keeps alive, which the compiler must also treat as roots when it collects.
+    vm_roots: Vec<Value>,
+}
+
+struct FunctionCompiler {
+    function: ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
 }
 
 struct Parser {
     current: Option<Token>,
     previous: Option<Token>,
-    had_error: bool,
     panic_mode: bool,
+    // Error tokens the scanner skipped on the way to current, reported once the parser moves on.
+    skipped_errors: Vec<Token>,
+}
+
+struct Local {
+    name: String,
+    // None until the variable's initializer has been compiled.
+    depth: Option<usize>,
+    is_captured: bool,
+}
+
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+struct Upvalue {
+    index: u8,
+    // Whether the upvalue captures a local of the enclosing function, or one of its upvalues.
+    is_local: bool,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
@@ -52,184 +96,712 @@ impl From<u8> for Precedence {
 }
 
 struct ParseRule {
-    prefix: Option<fn(&mut Compiler) -> ()>,
-    infix: Option<fn(&mut Compiler) -> ()>,
+    prefix: Option<fn(&mut Compiler, bool) -> ()>,
+    infix: Option<fn(&mut Compiler, bool) -> ()>,
     precedence: Precedence,
 }
 
 const RULES: [ParseRule; 40] = [
-    ParseRule {prefix: Some(Compiler::grouping), infix: None,                   precedence: Precedence::None},   // TOKEN_LEFT_PAREN
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_RIGHT_PAREN
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_LEFT_BRACE
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_RIGHT_BRACE
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_COMMA
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_DOT
-    ParseRule {prefix: Some(Compiler::unary),    infix: Some(Compiler::binary), precedence: Precedence::Term},   // TOKEN_MINUS
-    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Term},   // TOKEN_PLUS
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_SEMICOLON
-    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Factor}, // TOKEN_SLASH
-    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Factor}, // TOKEN_STAR
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_BANG
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_BANG_EQUAL
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_EQUAL
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_EQUAL_EQUAL
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_GREATER
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_GREATER_EQUAL
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_LESS
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_LESS_EQUAL
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_IDENTIFIER
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_STRING
-    ParseRule {prefix: Some(Compiler::number),   infix: None,                   precedence: Precedence::None},   // TOKEN_NUMBER
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_AND
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_CLASS
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_ELSE
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_FALSE
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_FOR
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_FUN
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_IF
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_NIL
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_OR
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_PRINT
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_RETURN
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_SUPER
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_THIS
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_TRUE
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_VAR
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_WHILE
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_ERROR
-    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},   // TOKEN_EOF
+    ParseRule {prefix: Some(Compiler::grouping), infix: Some(Compiler::call),   precedence: Precedence::Call},       // TOKEN_LEFT_PAREN
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_RIGHT_PAREN
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_LEFT_BRACE
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_RIGHT_BRACE
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_COMMA
+    ParseRule {prefix: None,                     infix: Some(Compiler::dot),    precedence: Precedence::Call},       // TOKEN_DOT
+    ParseRule {prefix: Some(Compiler::unary),    infix: Some(Compiler::binary), precedence: Precedence::Term},       // TOKEN_MINUS
+    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Term},       // TOKEN_PLUS
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_SEMICOLON
+    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Factor},     // TOKEN_SLASH
+    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Factor},     // TOKEN_STAR
+    ParseRule {prefix: Some(Compiler::unary),    infix: None,                   precedence: Precedence::None},       // TOKEN_BANG
+    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Equality},   // TOKEN_BANG_EQUAL
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_EQUAL
+    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Equality},   // TOKEN_EQUAL_EQUAL
+    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Comparison}, // TOKEN_GREATER
+    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Comparison}, // TOKEN_GREATER_EQUAL
+    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Comparison}, // TOKEN_LESS
+    ParseRule {prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Comparison}, // TOKEN_LESS_EQUAL
+    ParseRule {prefix: Some(Compiler::variable), infix: None,                   precedence: Precedence::None},       // TOKEN_IDENTIFIER
+    ParseRule {prefix: Some(Compiler::string),   infix: None,                   precedence: Precedence::None},       // TOKEN_STRING
+    ParseRule {prefix: Some(Compiler::number),   infix: None,                   precedence: Precedence::None},       // TOKEN_NUMBER
+    ParseRule {prefix: None,                     infix: Some(Compiler::and),    precedence: Precedence::And},        // TOKEN_AND
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_CLASS
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_ELSE
+    ParseRule {prefix: Some(Compiler::literal),  infix: None,                   precedence: Precedence::None},       // TOKEN_FALSE
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_FOR
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_FUN
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_IF
+    ParseRule {prefix: Some(Compiler::literal),  infix: None,                   precedence: Precedence::None},       // TOKEN_NIL
+    ParseRule {prefix: None,                     infix: Some(Compiler::or),     precedence: Precedence::Or},         // TOKEN_OR
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_PRINT
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_RETURN
+    ParseRule {prefix: Some(Compiler::super_),   infix: None,                   precedence: Precedence::None},       // TOKEN_SUPER
+    ParseRule {prefix: Some(Compiler::this),     infix: None,                   precedence: Precedence::None},       // TOKEN_THIS
+    ParseRule {prefix: Some(Compiler::literal),  infix: None,                   precedence: Precedence::None},       // TOKEN_TRUE
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_VAR
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_WHILE
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_ERROR
+    ParseRule {prefix: None,                     infix: None,                   precedence: Precedence::None},       // TOKEN_EOF
 ];
 
 impl Compiler {
-    pub fn compile(source: String) -> Result<Chunk, u8> {
+    pub fn compile(source: Rc<str>, heap: &mut Heap, vm_roots: Vec<Value>) -> Result<ObjRef, Vec<Diagnostic>> {
+        // The compiler holds on to the heap while it runs so that the parse functions in RULES
+        // can allocate objects without threading a lifetime through every fn pointer.
         let mut compiler = Self {
             source,
-            chunk: Chunk::new(),
+            heap: mem::take(heap),
             scanner: Scanner::new(),
-            parser: Parser { current: None, previous: None, had_error: false, panic_mode: false },
+            parser: Parser { current: None, previous: None, panic_mode: false, skipped_errors: Vec::new() },
+            diagnostics: Vec::new(),
+            compilers: Vec::new(),
+            classes: Vec::new(),
+            vm_roots,
         };
+        compiler.init_compiler(FunctionType::Script);
 
         compiler.advance();
-        compiler.expression();
-        compiler.consume(TokenType::Eof, "Expect end of expression.");
-        compiler.end_compiler();
 
-        if compiler.parser.had_error {
-            return Err(0);
+        while !compiler.match_token(TokenType::Eof) {
+            compiler.declaration();
+        }
+
+        let (function, _) = compiler.end_compiler();
+        *heap = mem::take(&mut compiler.heap);
+
+        if !compiler.diagnostics.is_empty() {
+            return Err(compiler.diagnostics);
+        }
+        Ok(heap.new_function(function))
+    }
+
+    fn init_compiler(&mut self, function_type: FunctionType) {
+        let name = match function_type {
+            FunctionType::Script => None,
+            _ => {
+                self.collect_garbage_if_needed();
+                let name = self.parser.previous.as_ref().unwrap();
+                Some(self.heap.copy_string(&self.source[name.label_start..name.label_end]))
+            }
+        };
+
+        let mut locals = Vec::with_capacity(UINT8_COUNT);
+        // Slot zero holds the function being called, or the receiver for methods.
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            _ => "",
+        };
+        locals.push(Local {
+            name: slot_zero.to_string(),
+            depth: Some(0),
+            is_captured: false,
+        });
+
+        self.compilers.push(FunctionCompiler {
+            function: ObjFunction::new(name, Rc::clone(&self.source)),
+            function_type,
+            locals,
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        });
+    }
+
+    fn collect_garbage_if_needed(&mut self) {
+        if !self.heap.should_collect() {
+            return;
+        }
+
+        for &root in &self.vm_roots {
+            self.heap.mark_value(root);
         }
-        Ok(compiler.chunk)
+        for compiler in &self.compilers {
+            if let Some(name) = compiler.function.name {
+                self.heap.mark_object(name);
+            }
+            for &constant in &compiler.function.chunk.constants {
+                self.heap.mark_value(constant);
+            }
+        }
+        self.heap.collect_garbage();
+    }
+
+    fn current(&self) -> &FunctionCompiler {
+        self.compilers.last().unwrap()
+    }
+
+    fn current_mut(&mut self) -> &mut FunctionCompiler {
+        self.compilers.last_mut().unwrap()
+    }
+
+    fn current_chunk(&self) -> &Chunk {
+        &self.current().function.chunk
+    }
+
+    fn current_chunk_mut(&mut self) -> &mut Chunk {
+        &mut self.current_mut().function.chunk
     }
 
     fn advance(&mut self) {
+        self.report_skipped_errors();
         self.parser.previous = mem::take(&mut self.parser.current);
 
         loop {
             let source_bytes = self.source.as_bytes();
-            self.parser.current = Some(self.scanner.scan_token(source_bytes));
-            if self.parser.current.as_ref().unwrap().token_type != TokenType::Error {
+            let token = self.scanner.scan_token(source_bytes);
+            if token.error.is_none() {
+                self.parser.current = Some(token);
                 break;
             }
+            self.parser.skipped_errors.push(token);
+        }
+    }
 
-            self.error_at_current("Something was erroneous.");
+    // The scanner runs a token ahead of the parser, so a lexical error waits here until the
+    // parser is done with the token before it. Otherwise panic mode would hide a syntax error the
+    // parser was about to report there. A lexical error can't be caused by an earlier error, so
+    // panic mode doesn't hide it either.
+    fn report_skipped_errors(&mut self) {
+        for token in mem::take(&mut self.parser.skipped_errors) {
+            if let Some(error) = token.error {
+                self.parser.panic_mode = false;
+                self.error_at(token.span(), Code::Lexical, &error.to_string());
+            }
         }
     }
 
-    // These error functions really don't play well with the borrow checker. Not sure how to
-    // structure it to make it happy.
-    fn error_at_current(&mut self, message: &str) {
+    fn error_at_current(&mut self, code: Code, message: &str) {
+        self.report_skipped_errors();
+        let span = self.parser.current.as_ref().unwrap().span();
+        self.error_at(span, code, message);
+    }
+
+    fn error(&mut self, code: Code, message: &str) {
+        let span = self.parser.previous.as_ref().unwrap().span();
+        self.error_at(span, code, message);
+    }
+
+    fn error_at(&mut self, span: Span, code: Code, message: &str) {
         if self.parser.panic_mode {
             return;
         }
         self.parser.panic_mode = true;
 
-        let token = self.parser.current.as_ref().unwrap();
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            code,
+            message: message.to_string(),
+            span,
+        });
+    }
 
-        eprint!("[line {}] Error", token.line);
+    fn format_token(&self, token: &Token) -> &str {
+        &self.source[token.label_start..token.label_end]
+    }
 
-        match token.token_type {
-            TokenType::Eof => eprint!(" at end"),
-            _ => eprint!(" at '{}'", self.format_token(token)),
-        }
+    fn previous_lexeme(&self) -> String {
+        self.format_token(self.parser.previous.as_ref().unwrap()).to_string()
+    }
 
-        eprintln!(": {}", message);
-        self.parser.had_error = true;
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
     }
 
-    fn error(&mut self, message: &str) {
+    fn declaration(&mut self) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
         if self.parser.panic_mode {
-            return;
+            self.synchronize();
         }
-        self.parser.panic_mode = true;
+    }
 
-        let token = self.parser.previous.as_ref().unwrap();
+    // Skips tokens until a likely statement boundary, so errors after it are reported too.
+    fn synchronize(&mut self) {
+        self.parser.panic_mode = false;
+
+        while !self.check(TokenType::Eof) {
+            if self.parser.previous.as_ref().unwrap().token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.parser.current.as_ref().unwrap().token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous_lexeme();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
 
-        eprint!("[line {}] Error", token.line);
+        self.emit_indexed(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
 
-        match token.token_type {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error => (),
-            _ => eprint!(" at '{}'", self.format_token(&token)),
+        self.classes.push(ClassCompiler { has_superclass: false });
+
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            if class_name == self.previous_lexeme() {
+                self.error(Code::Semantic, "A class can't inherit from itself.");
+            }
+
+            self.begin_scope();
+            self.add_local("super".to_string());
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            self.emit_byte(OpCode::Inherit as u8);
+            self.classes.last_mut().unwrap().has_superclass = true;
         }
 
-        eprintln!(": {}", message);
-        self.parser.had_error = true;
+        self.named_variable(&class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
     }
 
-    fn format_token(&self, token: &Token) -> &str {
-        &self.source[token.label_start..token.label_end]
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous_lexeme();
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if name == "init" { FunctionType::Initializer } else { FunctionType::Method };
+        self.function(function_type);
+        self.emit_indexed(OpCode::Method, constant);
     }
 
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        self.init_compiler(function_type);
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.current_mut().function.arity += 1;
+                if self.current().function.arity > 255 {
+                    self.error_at_current(Code::Limit, "Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // Collect while the function is still reachable through self.compilers.
+        self.collect_garbage_if_needed();
+        let (function, upvalues) = self.end_compiler();
+        let function = self.heap.new_function(function);
+        let constant = self.make_constant(Value::Obj(function));
+        self.emit_indexed(OpCode::Closure, constant);
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.");
+
+        self.define_variable(global);
+    }
+
+    fn statement(&mut self) {
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop as u8); // Condition.
+        }
+
+        if !self.match_token(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit_byte(OpCode::Pop as u8);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop as u8); // Condition.
+        }
+
+        self.end_scope();
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self.match_token(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn return_statement(&mut self) {
+        if self.current().function_type == FunctionType::Script {
+            self.error(Code::Semantic, "Can't return from top-level code.");
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current().function_type == FunctionType::Initializer {
+                self.error(Code::Semantic, "Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return as u8);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::Print as u8);
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::Pop as u8);
     }
 
     fn consume(&mut self, token_type: TokenType, message: &str) {
-        if self.parser.current.as_ref().unwrap().token_type == token_type {
+        if self.check(token_type) {
             self.advance();
             return;
         }
 
-        self.error_at_current(message);
+        self.error_at_current(Code::Syntax, message);
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.parser.current.as_ref().unwrap().token_type == token_type
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
     }
 
-    fn end_compiler(&mut self) {
+    fn end_compiler(&mut self) -> (ObjFunction, Vec<Upvalue>) {
         self.emit_return();
+        let compiler = self.compilers.pop().unwrap();
+        (compiler.function, compiler.upvalues)
     }
 
-    fn binary(&mut self) {
-        let operator_type = self.parser.previous.as_ref().unwrap().token_type;
+    fn begin_scope(&mut self) {
+        self.current_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.current_mut().scope_depth -= 1;
+
+        let scope_depth = self.current().scope_depth;
+        while let Some(local) = self.current().locals.last().filter(|local| local.depth > Some(scope_depth)) {
+            if local.is_captured {
+                self.emit_byte(OpCode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(OpCode::Pop as u8);
+            }
+            self.current_mut().locals.pop();
+        }
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self.parser.previous.as_ref().unwrap();
+        let (operator_type, operator_span) = (operator.token_type, operator.span());
         let rule = self.get_rule(operator_type);
         self.parse_precedence((rule.precedence as u8 + 1).into());
 
-        match operator_type {
-            TokenType::Plus => self.emit_byte(OpCode::Add as u8),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract as u8),
-            TokenType::Star => self.emit_byte(OpCode::Multiply as u8),
-            TokenType::Slash => self.emit_byte(OpCode::Divide as u8),
+        let opcodes: &[OpCode] = match operator_type {
+            TokenType::BangEqual => &[OpCode::Equal, OpCode::Not],
+            TokenType::EqualEqual => &[OpCode::Equal],
+            TokenType::Greater => &[OpCode::Greater],
+            TokenType::GreaterEqual => &[OpCode::Less, OpCode::Not],
+            TokenType::Less => &[OpCode::Less],
+            TokenType::LessEqual => &[OpCode::Greater, OpCode::Not],
+            TokenType::Plus => &[OpCode::Add],
+            TokenType::Minus => &[OpCode::Subtract],
+            TokenType::Star => &[OpCode::Multiply],
+            TokenType::Slash => &[OpCode::Divide],
+            _ => &[], // Unreachable.
+        };
+        for &opcode in opcodes {
+            self.emit_byte_at(opcode as u8, operator_span);
+        }
+    }
+
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name_span = self.parser.previous.as_ref().unwrap().span();
+        let name = self.previous_lexeme();
+        let name = self.identifier_constant(&name);
+
+        // Errors in setting or calling the property point at its name, not the end of the
+        // value or arguments parsed before the instruction.
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_indexed_at(OpCode::SetProperty, name, name_span);
+        } else if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_indexed_at(OpCode::Invoke, name, name_span);
+            self.emit_byte_at(arg_count, name_span);
+        } else {
+            self.emit_indexed(OpCode::GetProperty, name);
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.parser.previous.as_ref().unwrap().token_type {
+            TokenType::False => self.emit_byte(OpCode::False as u8),
+            TokenType::Nil => self.emit_byte(OpCode::Nil as u8),
+            TokenType::True => self.emit_byte(OpCode::True as u8),
             _ => (), // Unreachable.
         }
     }
 
-    fn grouping(&mut self) {
+    fn call(&mut self, _can_assign: bool) {
+        let paren_span = self.parser.previous.as_ref().unwrap().span();
+        let arg_count = self.argument_list();
+        self.emit_byte_at(OpCode::Call as u8, paren_span);
+        self.emit_byte_at(arg_count, paren_span);
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
     }
 
-    fn number(&mut self) {
+    fn number(&mut self, _can_assign: bool) {
         let token = self.parser.previous.as_ref().unwrap();
-        let value: Value = self.source[token.label_start..token.label_end].parse().unwrap_or_default();
-        self.emit_constant(value);
+        let value: f64 = self.source[token.label_start..token.label_end].parse().unwrap_or_default();
+        self.emit_constant(Value::Number(value));
     }
 
-    fn unary(&mut self) {
-        let operator_type = self.parser.previous.as_ref().unwrap().token_type;
+    fn string(&mut self, _can_assign: bool) {
+        self.collect_garbage_if_needed();
+        let token = self.parser.previous.as_ref().unwrap();
+        // Trim the surrounding quotes.
+        let chars = &self.source[token.label_start + 1..token.label_end - 1];
+        let string = self.heap.copy_string(chars);
+        self.emit_constant(Value::Obj(string));
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.previous_lexeme();
+        self.named_variable(&name, can_assign);
+    }
+
+    fn named_variable(&mut self, name: &str, can_assign: bool) {
+        let name_span = self.parser.previous.as_ref().unwrap().span();
+        let compiler = self.compilers.len() - 1;
+        let (get_op, set_op, arg) = if let Some(arg) = self.resolve_local(compiler, name) {
+            (OpCode::GetLocal, OpCode::SetLocal, arg as usize)
+        } else if let Some(arg) = self.resolve_upvalue(compiler, name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, arg as usize)
+        } else {
+            (OpCode::GetGlobal, OpCode::SetGlobal, self.identifier_constant(name))
+        };
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_indexed_at(set_op, arg, name_span);
+        } else {
+            self.emit_indexed(get_op, arg);
+        }
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error(Code::Semantic, "Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => self.error(Code::Semantic, "Can't use 'super' in a class with no superclass."),
+            _ => (),
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name_span = self.parser.previous.as_ref().unwrap().span();
+        let name = self.previous_lexeme();
+        let name = self.identifier_constant(&name);
+
+        self.named_variable("this", false);
+        if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable("super", false);
+            self.emit_indexed_at(OpCode::SuperInvoke, name, name_span);
+            self.emit_byte_at(arg_count, name_span);
+        } else {
+            self.named_variable("super", false);
+            self.emit_indexed(OpCode::GetSuper, name);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error(Code::Semantic, "Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.parser.previous.as_ref().unwrap();
+        let (operator_type, operator_span) = (operator.token_type, operator.span());
 
         // Compile the operand.
         self.parse_precedence(Precedence::Unary);
 
         // Emit the operator instruction.
         match operator_type {
-          TokenType::Minus => self.emit_byte(OpCode::Negate as u8),
+          TokenType::Bang => self.emit_byte_at(OpCode::Not as u8, operator_span),
+          TokenType::Minus => self.emit_byte_at(OpCode::Negate as u8, operator_span),
           _ => (), // Unreachable.
         }
     }
@@ -237,41 +809,237 @@ impl Compiler {
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
 
+        let can_assign = precedence <= Precedence::Assignment;
         if let Some(prefix_rule) = self.get_rule(self.parser.previous.as_ref().unwrap().token_type).prefix {
-            prefix_rule(self);
+            prefix_rule(self, can_assign);
         } else {
-            self.error("Expect expression.");
+            self.error(Code::Syntax, "Expect expression.");
             return;
         };
 
         while precedence <= self.get_rule(self.parser.current.as_ref().unwrap().token_type).precedence {
             self.advance();
             if let Some(infix_rule) = self.get_rule(self.parser.previous.as_ref().unwrap().token_type).infix {
-                infix_rule(self);
+                infix_rule(self, can_assign);
             }
         }
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error(Code::Syntax, "Invalid assignment target.");
+        }
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        self.collect_garbage_if_needed();
+        let name = self.heap.copy_string(name);
+        self.make_constant(Value::Obj(name))
+    }
+
+    fn resolve_local(&mut self, compiler: usize, name: &str) -> Option<u8> {
+        let (slot, local) = self.compilers[compiler].locals.iter().enumerate().rev().find(|(_, local)| local.name == name)?;
+        if local.depth.is_none() {
+            self.error(Code::Semantic, "Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    fn add_upvalue(&mut self, compiler: usize, index: u8, is_local: bool) -> u8 {
+        let upvalues = &self.compilers[compiler].upvalues;
+        if let Some(existing) = upvalues.iter().position(|upvalue| upvalue.index == index && upvalue.is_local == is_local) {
+            return existing as u8;
+        }
+
+        if upvalues.len() == UINT8_COUNT {
+            self.error(Code::Limit, "Too many closure variables in function.");
+            return 0;
+        }
+
+        let compiler = &mut self.compilers[compiler];
+        compiler.upvalues.push(Upvalue { index, is_local });
+        compiler.function.upvalue_count = compiler.upvalues.len();
+        (compiler.upvalues.len() - 1) as u8
+    }
+
+    fn resolve_upvalue(&mut self, compiler: usize, name: &str) -> Option<u8> {
+        if compiler == 0 {
+            return None;
+        }
+        let enclosing = compiler - 1;
+
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.compilers[enclosing].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(compiler, local, true));
+        }
+
+        let upvalue = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(compiler, upvalue, false))
+    }
+
+    fn add_local(&mut self, name: String) {
+        if self.current().locals.len() == UINT8_COUNT {
+            self.error(Code::Limit, "Too many local variables in function.");
+            return;
+        }
+
+        self.current_mut().locals.push(Local { name, depth: None, is_captured: false });
+    }
+
+    fn declare_variable(&mut self) {
+        let scope_depth = self.current().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous_lexeme();
+        let already_declared = self.current().locals.iter().rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == name);
+        if already_declared {
+            self.error(Code::Semantic, "Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn parse_variable(&mut self, error_message: &str) -> usize {
+        self.consume(TokenType::Identifier, error_message);
+
+        self.declare_variable();
+        if self.current().scope_depth > 0 {
+            return 0;
+        }
+
+        let name = self.previous_lexeme();
+        self.identifier_constant(&name)
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope_depth = self.current().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+        self.current_mut().locals.last_mut().unwrap().depth = Some(scope_depth);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.error(Code::Limit, "Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count as u8
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.current().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_indexed(OpCode::DefineGlobal, global);
     }
 
     fn get_rule(&self, token_type: TokenType) -> &ParseRule {
         &RULES[token_type as usize]
     }
 
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop as u8);
+
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error(Code::Limit, "Loop body too large.");
+        }
+
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.emit_bytes(high, low);
+    }
+
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_bytes(0xff, 0xff);
+        self.current_chunk().code.len() - 2
+    }
+
     fn emit_return(&mut self) {
+        if self.current().function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal as u8, 0);
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+
         self.emit_byte(OpCode::Return as u8);
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = self.chunk.add_constant(value);
-        if constant > u8::MAX as usize {
-            self.error("Too many constants in one chunk.");
+    fn make_constant(&mut self, value: Value) -> usize {
+        let Some(constant) = self.current_chunk_mut().add_constant(value) else {
+            self.error(Code::Limit, "Too many constants in one chunk.");
             return 0;
-        }
-        constant as u8
+        };
+        constant
     }
 
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::Constant as u8, constant);
+        self.emit_indexed(OpCode::Constant, constant);
+    }
+
+    fn emit_indexed(&mut self, opcode: OpCode, index: usize) {
+        let span = self.parser.previous.as_ref().unwrap().span();
+        self.emit_indexed_at(opcode, index, span);
+    }
+
+    // Emits the opcode and its index operand. Indices that don't fit in a byte are only ever
+    // constants, which switch to the long form of the opcode and a 24-bit index.
+    fn emit_indexed_at(&mut self, opcode: OpCode, index: usize, span: Span) {
+        if let Ok(index) = u8::try_from(index) {
+            self.emit_byte_at(opcode as u8, span);
+            self.emit_byte_at(index, span);
+            return;
+        }
+
+        let long = match opcode {
+            OpCode::Constant => OpCode::ConstantLong,
+            OpCode::GetGlobal => OpCode::GetGlobalLong,
+            OpCode::DefineGlobal => OpCode::DefineGlobalLong,
+            OpCode::SetGlobal => OpCode::SetGlobalLong,
+            OpCode::GetProperty => OpCode::GetPropertyLong,
+            OpCode::SetProperty => OpCode::SetPropertyLong,
+            OpCode::GetSuper => OpCode::GetSuperLong,
+            OpCode::Invoke => OpCode::InvokeLong,
+            OpCode::SuperInvoke => OpCode::SuperInvokeLong,
+            OpCode::Closure => OpCode::ClosureLong,
+            OpCode::Class => OpCode::ClassLong,
+            OpCode::Method => OpCode::MethodLong,
+            _ => unreachable!("Only constant indices outgrow a byte."),
+        };
+        let [_, high, middle, low] = (index as u32).to_be_bytes();
+        for byte in [long as u8, high, middle, low] {
+            self.emit_byte_at(byte, span);
+        }
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself.
+        let jump = self.current_chunk().code.len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.error(Code::Limit, "Too much code to jump over.");
+        }
+
+        let [high, low] = (jump as u16).to_be_bytes();
+        self.current_chunk_mut().code[offset] = high;
+        self.current_chunk_mut().code[offset + 1] = low;
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -280,6 +1048,94 @@ impl Compiler {
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write_chunk(byte, self.parser.previous.as_ref().unwrap().line);
+        let span = self.parser.previous.as_ref().unwrap().span();
+        self.emit_byte_at(byte, span);
+    }
+
+    // Attributes the byte to a token other than the one just consumed, such as the operator of
+    // a binary expression whose right operand has already been parsed.
+    fn emit_byte_at(&mut self, byte: u8, span: Span) {
+        self.current_chunk_mut().write_chunk(byte, span);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> (Heap, Result<ObjRef, Vec<Diagnostic>>) {
+        let mut heap = Heap::new();
+        let result = Compiler::compile(Rc::from(source), &mut heap, Vec::new());
+        (heap, result)
+    }
+
+    // The source each instruction with the opcode points at, in code order.
+    fn spans_of(source: &str, opcode: OpCode) -> Vec<&str> {
+        let (heap, result) = compile(source);
+        let Ok(function) = result else {
+            panic!("{:?} should compile.", source);
+        };
+        let chunk = &heap.as_function(function).unwrap().chunk;
+        chunk
+            .disassemble(&heap)
+            .iter()
+            .filter(|instruction| instruction.opcode == Some(opcode))
+            .map(|instruction| {
+                let span = chunk.span_at(instruction.offset);
+                &source[span.start..span.end]
+            })
+            .collect()
+    }
+
+    fn diagnostics(source: &str) -> Vec<(usize, Code, String)> {
+        let (_, result) = compile(source);
+        let Err(diagnostics) = result else {
+            panic!("{:?} shouldn't compile.", source);
+        };
+        diagnostics.into_iter().map(|diagnostic| (diagnostic.span.line, diagnostic.code, diagnostic.message)).collect()
+    }
+
+    #[test]
+    fn lexical_errors_dont_hide_the_syntax_error_before_them() {
+        assert_eq!(
+            diagnostics("var a = ;\n@"),
+            [
+                (1, Code::Syntax, "Expect expression.".to_string()),
+                (2, Code::Lexical, "Unexpected character '@'.".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn every_independent_error_is_reported() {
+        let source = "var a = ;\n\
+                      print 1 @;\n\
+                      var b = 1 $ 2;\n\
+                      class {}\n\
+                      fun f() { return this; }\n\
+                      print \"unterminated";
+        assert_eq!(
+            diagnostics(source),
+            [
+                (1, Code::Syntax, "Expect expression.".to_string()),
+                (2, Code::Lexical, "Unexpected character '@'.".to_string()),
+                (3, Code::Lexical, "Unexpected character '$'.".to_string()),
+                (4, Code::Syntax, "Expect class name.".to_string()),
+                (5, Code::Semantic, "Can't use 'this' outside of a class.".to_string()),
+                (6, Code::Lexical, "Unterminated string.".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_point_at_the_callee_not_the_closing_paren() {
+        assert_eq!(spans_of("f(1, 2);", OpCode::Call), ["("]);
+        assert_eq!(spans_of("a.method(1);", OpCode::Invoke), ["method"]);
+    }
+
+    #[test]
+    fn assignments_point_at_the_name_not_the_value() {
+        assert_eq!(spans_of("a = 1 + 2;", OpCode::SetGlobal), ["a"]);
+        assert_eq!(spans_of("a.field = 1 + 2;", OpCode::SetProperty), ["field"]);
     }
 }
@@ -0,0 +1,168 @@
+use std::fmt::Write;
+
+use crate::scanner::Span;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+        }
+    }
+}
+
+// The kind of problem a diagnostic reports, for tools that want to group or filter them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    // The scanner couldn't make a token out of the source.
+    Lexical,
+    // The tokens don't form a valid program.
+    Syntax,
+    // The program parses but uses a variable, `this`, `super` or `return` where it isn't allowed.
+    Semantic,
+    // The program goes past one of the fixed limits of the bytecode.
+    Limit,
+}
+
+impl Code {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Code::Lexical => "lexical",
+            Code::Syntax => "syntax",
+            Code::Semantic => "semantic",
+            Code::Limit => "limit",
+        }
+    }
+}
+
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub message: String,
+    pub span: Span,
+}
+
+// Renders a diagnostic the way the command line shows it: the location and message, then the
+// source line with the span underlined.
+pub fn render_text(diagnostic: &Diagnostic, source: &str) -> String {
+    let span = diagnostic.span;
+    let at_end = span.start >= source.len();
+
+    let mut text = format!("[line {}] Error", span.line);
+    if at_end {
+        text.push_str(" at end");
+    } else if diagnostic.code != Code::Lexical {
+        let _ = write!(text, " at '{}'", String::from_utf8_lossy(&source.as_bytes()[span.start..span.end]));
+    }
+    let _ = write!(text, ": {}", diagnostic.message);
+
+    if !at_end {
+        text.push('\n');
+        text.push_str(&span.underline(source));
+    }
+    text
+}
+
+// Renders the diagnostics as a JSON array, one object per diagnostic, for editors to consume.
+pub fn render_json(diagnostics: &[Diagnostic]) -> String {
+    let mut json = String::from("[");
+    for (i, diagnostic) in diagnostics.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let span = diagnostic.span;
+        let _ = write!(
+            json,
+            "{{\"severity\":\"{}\",\"code\":\"{}\",\"message\":{},\"line\":{},\"column\":{},\"start\":{},\"end\":{}}}",
+            diagnostic.severity.as_str(),
+            diagnostic.code.as_str(),
+            json_string(&diagnostic.message),
+            span.line,
+            span.column,
+            span.start,
+            span.end,
+        );
+    }
+    json.push(']');
+    json
+}
+
+fn json_string(chars: &str) -> String {
+    let mut json = String::from("\"");
+    for c in chars.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(code: Code, message: &str, start: usize, end: usize, line: usize, column: usize) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            message: message.to_string(),
+            span: Span { start, end, line, column },
+        }
+    }
+
+    #[test]
+    fn text_names_the_token_and_underlines_it() {
+        let source = "print 1;\nvar a = ;\n";
+        let text = render_text(&diagnostic(Code::Syntax, "Expect expression.", 17, 18, 2, 9), source);
+        assert_eq!(text, "[line 2] Error at ';': Expect expression.\n    var a = ;\n            ^");
+    }
+
+    #[test]
+    fn text_for_lexical_errors_and_the_end_of_input() {
+        let source = "var a = @;";
+        let lexical = render_text(&diagnostic(Code::Lexical, "Unexpected character.", 8, 9, 1, 9), source);
+        assert_eq!(lexical, "[line 1] Error: Unexpected character.\n    var a = @;\n            ^");
+
+        let at_end = render_text(&diagnostic(Code::Syntax, "Expect ';' after value.", 10, 10, 1, 11), source);
+        assert_eq!(at_end, "[line 1] Error at end: Expect ';' after value.");
+    }
+
+    #[test]
+    fn json_lists_every_diagnostic() {
+        assert_eq!(render_json(&[]), "[]");
+
+        let diagnostics = [
+            diagnostic(Code::Syntax, "Expect expression.", 8, 9, 1, 9),
+            diagnostic(Code::Limit, "Too many constants in one chunk.", 20, 23, 2, 4),
+        ];
+        assert_eq!(
+            render_json(&diagnostics),
+            concat!(
+                r#"[{"severity":"error","code":"syntax","message":"Expect expression.","line":1,"column":9,"start":8,"end":9},"#,
+                r#"{"severity":"error","code":"limit","message":"Too many constants in one chunk.","line":2,"column":4,"start":20,"end":23}]"#,
+            )
+        );
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string("say \"hi\" \\ bye"), r#""say \"hi\" \\ bye""#);
+        assert_eq!(json_string("a\nb\r\tc"), r#""a\nb\r\tc""#);
+        assert_eq!(json_string("\u{0}\u{1b}"), r#""\u0000\u001b""#);
+        assert_eq!(json_string("é"), "\"é\"");
+    }
+}
@@ -1,6 +1,11 @@
 mod chunk;
 mod compiler;
+mod diagnostic;
+mod memory;
+mod object;
 mod scanner;
+mod value;
+mod verifier;
 mod vm;
 
 use std::env;
@@ -10,28 +15,58 @@ use std::process;
 use vm::*;
 
 fn main() {
-    let argv = env::args().collect::<Vec<String>>();
+    let mut argv = env::args().collect::<Vec<String>>();
+    let mut vm = Vm::new();
+    vm.set_stress_gc(take_flag(&mut argv, "--stress-gc"));
+    let log_gc = take_flag(&mut argv, "--log-gc");
+    vm.set_log_gc(log_gc);
+    let json_diagnostics = take_flag(&mut argv, "--json-diagnostics");
+    if take_flag(&mut argv, "--disassemble") {
+        vm.set_disassemble(Some(Box::new(io::stderr())));
+    }
+    if take_flag(&mut argv, "--trace") {
+        #[cfg(feature = "trace-execution")]
+        vm.set_trace(Some(Box::new(io::stderr())));
+        #[cfg(not(feature = "trace-execution"))]
+        {
+            eprintln!("--trace requires building with the trace-execution feature.");
+            process::exit(64);
+        }
+    }
+
     let argc = argv.len();
-    match argc {
+    let result = match argc {
         1 => {
-            repl();
-        }
-        2 => {
-            run_file(&argv[1]);
+            repl(&mut vm, json_diagnostics);
+            InterpretResult::Ok
         }
+        2 => run_file(&mut vm, &argv[1], json_diagnostics),
         _ => {
-            eprintln!("Usage: rustlox [path]");
+            eprintln!("Usage: rustlox [--stress-gc] [--log-gc] [--json-diagnostics] [--disassemble] [--trace] [path]");
             process::exit(64);
         }
+    };
+
+    // Reported however the program ended, so failing runs can be diagnosed too.
+    if log_gc {
+        eprintln!("bytes allocated: {}, next gc at: {}", vm.bytes_allocated(), vm.next_gc());
     }
-    println!("num args: {}", argc);
-    for arg in argv {
-        println!("arg: {}", arg);
+
+    match result {
+        InterpretResult::CompileError(_) | InterpretResult::InvalidBytecode(_) => process::exit(65),
+        InterpretResult::RuntimeError => process::exit(70),
+        InterpretResult::Ok => (),
     }
 }
 
-fn repl() {
-    let mut vm = Vm::new();
+// Removes every occurrence of the flag from the arguments, returning whether it was present.
+fn take_flag(argv: &mut Vec<String>, flag: &str) -> bool {
+    let argc = argv.len();
+    argv.retain(|arg| arg != flag);
+    argv.len() != argc
+}
+
+fn repl(vm: &mut Vm, json_diagnostics: bool) {
     let mut buffer = String::new();
     let stdin = io::stdin();
     let mut stdout = io::stdout();
@@ -39,22 +74,35 @@ fn repl() {
         print!("> ");
         stdout.flush().unwrap();
         buffer.clear();
-        stdin.read_line(&mut buffer).unwrap();
-        vm.interpret(buffer.clone());
+        // Stop at the end of input rather than interpreting empty lines forever.
+        if stdin.read_line(&mut buffer).unwrap() == 0 {
+            println!();
+            return;
+        }
+        let result = vm.interpret(buffer.clone());
+        report(&result, &buffer, json_diagnostics);
     }
 }
 
-fn run_file(path: &str) {
+fn run_file(vm: &mut Vm, path: &str, json_diagnostics: bool) -> InterpretResult {
     let source = fs::read_to_string(path).expect("Couldn't read source file");
-    let result = Vm::new().interpret(source);
+    let result = vm.interpret(source.clone());
+    report(&result, &source, json_diagnostics);
+    result
+}
 
+// Prints why the source couldn't run. The VM prints runtime errors itself, as they happen.
+fn report(result: &InterpretResult, source: &str, json_diagnostics: bool) {
     match result {
-        InterpretResult::CompileError => {
-            process::exit(65);
+        InterpretResult::CompileError(diagnostics) if json_diagnostics => {
+            eprintln!("{}", diagnostic::render_json(diagnostics));
         }
-        InterpretResult::RuntimeError => {
-            process::exit(70);
+        InterpretResult::CompileError(diagnostics) => {
+            for diagnostic in diagnostics {
+                eprintln!("{}", diagnostic::render_text(diagnostic, source));
+            }
         }
-        _ => {}
+        InterpretResult::InvalidBytecode(error) => eprintln!("{}", error),
+        InterpretResult::Ok | InterpretResult::RuntimeError => (),
     }
 }
@@ -0,0 +1,421 @@
+use std::{collections::{hash_map::RandomState, HashMap}, fmt, hash::BuildHasher};
+
+use crate::{object::*, value::Value};
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const INITIAL_NEXT_GC: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+// Objects live in slots that are reused once the collector frees them, so an ObjRef stays valid
+// exactly as long as the object it refers to is reachable.
+pub struct Heap {
+    objects: Vec<Option<Obj>>,
+    marks: Vec<bool>,
+    free_slots: Vec<usize>,
+    gray_stack: Vec<ObjRef>,
+    // Every string allocated on the heap is interned here, so two strings with the same
+    // characters are always the same object and can be compared by reference. Strings are
+    // bucketed by a hash of their characters, which only live in the string object itself.
+    strings: HashMap<u64, Vec<ObjRef>>,
+    string_hasher: RandomState,
+    bytes_allocated: usize,
+    next_gc: usize,
+    stress_gc: bool,
+    log_gc: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            marks: Vec::new(),
+            free_slots: Vec::new(),
+            gray_stack: Vec::new(),
+            strings: HashMap::new(),
+            string_hasher: RandomState::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_NEXT_GC,
+            stress_gc: false,
+            log_gc: false,
+        }
+    }
+
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.stress_gc = stress_gc;
+    }
+
+    pub fn set_log_gc(&mut self, log_gc: bool) {
+        self.log_gc = log_gc;
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn next_gc(&self) -> usize {
+        self.next_gc
+    }
+
+    // The heap can't see the roots held by the VM and the compiler, so it never collects on its
+    // own. Callers check this before allocating, mark their roots and then collect.
+    pub fn should_collect(&self) -> bool {
+        self.stress_gc || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn copy_string(&mut self, chars: &str) -> ObjRef {
+        let hash = self.string_hasher.hash_one(chars);
+        if let Some(interned) = self.find_string(hash, chars) {
+            return interned;
+        }
+        self.allocate_string(hash, chars.to_string())
+    }
+
+    pub fn take_string(&mut self, chars: String) -> ObjRef {
+        if cfg!(feature = "no-runtime-interning") {
+            return self.allocate(Obj::String(chars));
+        }
+
+        let hash = self.string_hasher.hash_one(&chars);
+        if let Some(interned) = self.find_string(hash, &chars) {
+            return interned;
+        }
+        self.allocate_string(hash, chars)
+    }
+
+    fn find_string(&self, hash: u64, chars: &str) -> Option<ObjRef> {
+        let bucket = self.strings.get(&hash)?;
+        bucket.iter().copied().find(|&string| self.as_string(string) == Some(chars))
+    }
+
+    pub fn new_function(&mut self, function: ObjFunction) -> ObjRef {
+        self.allocate(Obj::Function(function))
+    }
+
+    pub fn new_closure(&mut self, closure: ObjClosure) -> ObjRef {
+        self.allocate(Obj::Closure(closure))
+    }
+
+    pub fn new_upvalue(&mut self, slot: usize) -> ObjRef {
+        self.allocate(Obj::Upvalue(ObjUpvalue::Open(slot)))
+    }
+
+    pub fn new_class(&mut self, name: ObjRef) -> ObjRef {
+        self.allocate(Obj::Class(ObjClass { name, methods: HashMap::new() }))
+    }
+
+    pub fn new_instance(&mut self, class: ObjRef) -> ObjRef {
+        self.allocate(Obj::Instance(ObjInstance { class, fields: HashMap::new() }))
+    }
+
+    pub fn set_field(&mut self, instance: ObjRef, name: ObjRef, value: Value) {
+        self.grow(instance, |instance| {
+            if let Obj::Instance(instance) = instance {
+                instance.fields.insert(name, value);
+            }
+        });
+    }
+
+    pub fn add_methods(&mut self, class: ObjRef, methods: impl IntoIterator<Item = (ObjRef, ObjRef)>) {
+        self.grow(class, |class| {
+            if let Obj::Class(class) = class {
+                class.methods.extend(methods);
+            }
+        });
+    }
+
+    // Counts memory that grows with the program but isn't an object, such as the VM's globals,
+    // toward the next collection. It's never freed, so sweep has nothing to subtract.
+    pub fn grow_untracked(&mut self, bytes: usize) {
+        self.bytes_allocated += bytes;
+    }
+
+    // Applies a change that can grow an object after it was allocated, such as adding a field,
+    // and counts the growth. sweep subtracts the object's size as it is then, which is what was
+    // counted at allocation plus every change since.
+    fn grow(&mut self, obj: ObjRef, change: impl FnOnce(&mut Obj)) {
+        let object = self.get_mut(obj);
+        let before = object.size();
+        change(object);
+        let after = object.size();
+        self.bytes_allocated = self.bytes_allocated - before + after;
+    }
+
+    pub fn new_bound_method(&mut self, receiver: Value, method: ObjRef) -> ObjRef {
+        self.allocate(Obj::BoundMethod(ObjBoundMethod { receiver, method }))
+    }
+
+    pub fn new_native(&mut self, arity: usize, function: NativeFn) -> ObjRef {
+        self.allocate(Obj::Native(ObjNative { arity, function }))
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        self.objects[obj.0].as_ref().expect("Use of a freed object.")
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        self.objects[obj.0].as_mut().expect("Use of a freed object.")
+    }
+
+    pub fn as_string(&self, obj: ObjRef) -> Option<&str> {
+        match self.get(obj) {
+            Obj::String(chars) => Some(chars),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self, obj: ObjRef) -> Option<&ObjFunction> {
+        match self.get(obj) {
+            Obj::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self, obj: ObjRef) -> Option<&ObjClosure> {
+        match self.get(obj) {
+            Obj::Closure(closure) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue(&self, obj: ObjRef) -> Option<&ObjUpvalue> {
+        match self.get(obj) {
+            Obj::Upvalue(upvalue) => Some(upvalue),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue_mut(&mut self, obj: ObjRef) -> Option<&mut ObjUpvalue> {
+        match self.get_mut(obj) {
+            Obj::Upvalue(upvalue) => Some(upvalue),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self, obj: ObjRef) -> Option<&ObjClass> {
+        match self.get(obj) {
+            Obj::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self, obj: ObjRef) -> Option<&ObjInstance> {
+        match self.get(obj) {
+            Obj::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+
+    pub fn fmt_object(&self, f: &mut fmt::Formatter, obj: ObjRef) -> fmt::Result {
+        match self.get(obj) {
+            Obj::String(chars) => write!(f, "{}", chars),
+            Obj::Function(function) => self.fmt_function(f, function),
+            Obj::Closure(closure) => self.fmt_function(f, self.as_function(closure.function).unwrap()),
+            Obj::Upvalue(_) => write!(f, "upvalue"),
+            Obj::Class(class) => write!(f, "{}", self.as_string(class.name).unwrap()),
+            Obj::Instance(instance) => {
+                let class = self.as_class(instance.class).unwrap();
+                write!(f, "{} instance", self.as_string(class.name).unwrap())
+            }
+            Obj::BoundMethod(bound_method) => {
+                let closure = self.as_closure(bound_method.method).unwrap();
+                self.fmt_function(f, self.as_function(closure.function).unwrap())
+            }
+            Obj::Native(_) => write!(f, "<native fn>"),
+        }
+    }
+
+    fn fmt_function(&self, f: &mut fmt::Formatter, function: &ObjFunction) -> fmt::Result {
+        match function.name {
+            Some(name) => write!(f, "<fn {}>", self.as_string(name).unwrap()),
+            None => write!(f, "<script>"),
+        }
+    }
+
+    fn allocate_string(&mut self, hash: u64, chars: String) -> ObjRef {
+        let string = self.allocate(Obj::String(chars));
+        self.strings.entry(hash).or_default().push(string);
+        string
+    }
+
+    fn allocate(&mut self, obj: Obj) -> ObjRef {
+        self.bytes_allocated += obj.size();
+
+        if let Some(slot) = self.free_slots.pop() {
+            self.objects[slot] = Some(obj);
+            return ObjRef(slot);
+        }
+
+        self.objects.push(Some(obj));
+        self.marks.push(false);
+        ObjRef(self.objects.len() - 1)
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        mark(&mut self.marks, &mut self.gray_stack, obj);
+    }
+
+    // Traces everything reachable from the objects marked so far and frees the rest.
+    pub fn collect_garbage(&mut self) {
+        let before = self.bytes_allocated;
+        if self.log_gc {
+            eprintln!("-- gc begin");
+        }
+
+        self.trace_references();
+        let marks = &self.marks;
+        self.strings.retain(|_, bucket| {
+            bucket.retain(|string| marks[string.0]);
+            !bucket.is_empty()
+        });
+        self.sweep();
+
+        self.next_gc = self.bytes_allocated * GC_HEAP_GROW_FACTOR;
+
+        if self.log_gc {
+            eprintln!("-- gc end");
+            eprintln!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                before - self.bytes_allocated,
+                before,
+                self.bytes_allocated,
+                self.next_gc,
+            );
+        }
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(obj) = self.gray_stack.pop() {
+            self.blacken_object(obj);
+        }
+    }
+
+    fn blacken_object(&mut self, obj: ObjRef) {
+        let Heap { objects, marks, gray_stack, .. } = self;
+        match objects[obj.0].as_ref().unwrap() {
+            Obj::String(_) | Obj::Native(_) => (),
+            Obj::Function(function) => {
+                if let Some(name) = function.name {
+                    mark(marks, gray_stack, name);
+                }
+                for constant in &function.chunk.constants {
+                    if let Value::Obj(constant) = constant {
+                        mark(marks, gray_stack, *constant);
+                    }
+                }
+            }
+            Obj::Closure(closure) => {
+                mark(marks, gray_stack, closure.function);
+                for upvalue in &closure.upvalues {
+                    mark(marks, gray_stack, *upvalue);
+                }
+            }
+            Obj::Upvalue(ObjUpvalue::Closed(Value::Obj(closed))) => mark(marks, gray_stack, *closed),
+            Obj::Upvalue(_) => (),
+            Obj::Class(class) => {
+                mark(marks, gray_stack, class.name);
+                for (name, method) in &class.methods {
+                    mark(marks, gray_stack, *name);
+                    mark(marks, gray_stack, *method);
+                }
+            }
+            Obj::Instance(instance) => {
+                mark(marks, gray_stack, instance.class);
+                for (name, value) in &instance.fields {
+                    mark(marks, gray_stack, *name);
+                    if let Value::Obj(value) = value {
+                        mark(marks, gray_stack, *value);
+                    }
+                }
+            }
+            Obj::BoundMethod(bound_method) => {
+                if let Value::Obj(receiver) = bound_method.receiver {
+                    mark(marks, gray_stack, receiver);
+                }
+                mark(marks, gray_stack, bound_method.method);
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        for (slot, object) in self.objects.iter_mut().enumerate() {
+            if object.is_none() {
+                continue;
+            }
+
+            if self.marks[slot] {
+                self.marks[slot] = false;
+                continue;
+            }
+
+            let unreached = object.take().unwrap();
+            self.bytes_allocated -= unreached.size();
+            self.free_slots.push(slot);
+        }
+    }
+}
+
+fn mark(marks: &mut [bool], gray_stack: &mut Vec<ObjRef>, obj: ObjRef) {
+    if marks[obj.0] {
+        return;
+    }
+    marks[obj.0] = true;
+    gray_stack.push(obj);
+}
+
+// Built without interning, concatenations are fresh objects, which is what these tests rule out.
+#[cfg(all(test, not(feature = "no-runtime-interning")))]
+mod tests {
+    use std::mem;
+
+    use super::*;
+
+    #[test]
+    fn strings_are_interned_and_freed_from_the_table() {
+        let mut heap = Heap::new();
+        let a = heap.copy_string("interned");
+        let b = heap.take_string("inter".to_string() + "ned");
+        assert_eq!(a, b);
+        assert_ne!(a, heap.copy_string("other"));
+
+        // Nothing is marked, so the collector frees both strings and forgets them.
+        heap.collect_garbage();
+        assert!(heap.strings.is_empty());
+        let c = heap.copy_string("interned");
+        assert_eq!(heap.as_string(c), Some("interned"));
+    }
+
+    #[test]
+    fn fields_and_methods_count_toward_the_next_collection() {
+        let mut heap = Heap::new();
+        let name = heap.copy_string("C");
+        let class = heap.new_class(name);
+        let instance = heap.new_instance(class);
+        let allocated = heap.bytes_allocated();
+
+        for i in 0..1000 {
+            let field = heap.copy_string(&format!("field{}", i));
+            heap.set_field(instance, field, Value::Number(i as f64));
+            heap.add_methods(class, [(field, class)]);
+        }
+        let entries = mem::size_of::<(ObjRef, Value)>() + mem::size_of::<(ObjRef, ObjRef)>();
+        assert!(heap.bytes_allocated() >= allocated + 1000 * entries);
+
+        // Freeing everything subtracts exactly what was counted, growth included.
+        heap.collect_garbage();
+        assert_eq!(heap.bytes_allocated(), 0);
+    }
+}
@@ -0,0 +1,96 @@
+use std::{collections::HashMap, mem, rc::Rc};
+
+use crate::{chunk::Chunk, memory::ObjRef, value::Value};
+
+pub enum Obj {
+    String(String),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+}
+
+impl Obj {
+    // An estimate of the memory owned by the object, used to decide when to collect garbage.
+    pub fn size(&self) -> usize {
+        mem::size_of::<Obj>() + match self {
+            Obj::String(chars) => chars.capacity(),
+            Obj::Function(function) => function.chunk.size(),
+            Obj::Closure(closure) => closure.upvalues.capacity() * mem::size_of::<ObjRef>(),
+            Obj::Class(class) => class.methods.capacity() * mem::size_of::<(ObjRef, ObjRef)>(),
+            Obj::Instance(instance) => instance.fields.capacity() * mem::size_of::<(ObjRef, Value)>(),
+            Obj::Upvalue(_) | Obj::BoundMethod(_) | Obj::Native(_) => 0,
+        }
+    }
+}
+
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // None for the implicit function wrapping top-level code.
+    pub name: Option<ObjRef>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<ObjRef>, source: Rc<str>) -> Self {
+        Self {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(source),
+            name,
+        }
+    }
+
+    // A top-level function built straight from bytecode, for tests that need chunks the compiler
+    // never emits.
+    #[cfg(test)]
+    pub fn from_code(code: &[u8], constants: &[Value]) -> Self {
+        let mut function = Self::new(None, Rc::from(""));
+        let span = crate::scanner::Span { start: 0, end: 0, line: 1, column: 1 };
+        for &byte in code {
+            function.chunk.write_chunk(byte, span);
+        }
+        for &constant in constants {
+            function.chunk.add_constant(constant);
+        }
+        function
+    }
+}
+
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+pub enum ObjUpvalue {
+    // Still on the stack, at this slot.
+    Open(usize),
+    // Moved off the stack when the variable went out of scope.
+    Closed(Value),
+}
+
+pub struct ObjClass {
+    pub name: ObjRef,
+    pub methods: HashMap<ObjRef, ObjRef>,
+}
+
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+pub type NativeFn = fn(args: &[Value]) -> Value;
+
+pub struct ObjNative {
+    pub arity: usize,
+    pub function: NativeFn,
+}
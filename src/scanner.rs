@@ -1,7 +1,14 @@
+use std::fmt;
+
 pub struct Scanner {
     start: usize,
     current: usize,
     line: usize,
+    // Offset of the first byte of the line being scanned.
+    line_start: usize,
+    // Where the token being scanned begins.
+    start_line: usize,
+    start_column: usize,
 }
 
 impl Scanner {
@@ -10,13 +17,18 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
     pub fn scan_token(&mut self, source: &[u8]) -> Token {
-        self.skip_whitespace(source);
+        if let Some(error) = self.skip_whitespace(source) {
+            return error;
+        }
 
-        self.start = self.current;
+        self.begin_token();
 
         if self.is_at_end(source) {
             return self.make_token(TokenType::Eof);
@@ -53,27 +65,56 @@ impl Scanner {
             b'"' => self.string(source),
             c if Scanner::is_alpha(c) => self.identifier(source),
             c if c.is_ascii_digit() => self.number(source),
-            _ => self.error_token(),
+            _ => self.unexpected_character(source),
         }
     }
 
-    fn skip_whitespace(&mut self, source: &[u8]) {
+    fn begin_token(&mut self) {
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.start - self.line_start + 1;
+    }
+
+    // Skips whitespace and comments, returning an error token for a comment that never ends.
+    fn skip_whitespace(&mut self, source: &[u8]) -> Option<Token> {
         loop {
             match self.peek(source) {
                 Some(b' ' | b'\r' | b'\t') => {
                     self.advance(source);
                 },
                 Some(b'\n') => {
-                    self.line += 1;
                     self.advance(source);
+                    self.new_line();
                 },
                 Some(b'/') if self.peek_next(source) == Some(b'/') => {
                     while self.peek(source) != Some(b'\n') && !self.is_at_end(source) {
                         self.advance(source);
                     }
                 },
+                Some(b'/') if self.peek_next(source) == Some(b'*') => {
+                    // Block comments don't nest, so the first "*/" ends the comment.
+                    self.begin_token();
+                    self.advance(source);
+                    self.advance(source);
+                    loop {
+                        match self.peek(source) {
+                            None => return Some(self.error_token(ScanError::UnterminatedComment)),
+                            Some(b'*') if self.peek_next(source) == Some(b'/') => {
+                                self.advance(source);
+                                self.advance(source);
+                                break;
+                            },
+                            Some(c) => {
+                                self.advance(source);
+                                if c == b'\n' {
+                                    self.new_line();
+                                }
+                            },
+                        }
+                    }
+                },
                 _ => {
-                    return;
+                    return None;
                 }
             }
         }
@@ -126,14 +167,14 @@ impl Scanner {
     fn string(&mut self, source: &[u8]) -> Token {
         while let Some(c) = self.peek(source) {
             if c == b'"' { break; }
+            self.advance(source);
             if c == b'\n' {
-                self.line += 1;
+                self.new_line();
             }
-            self.advance(source);
         }
 
         if self.is_at_end(source) {
-            return self.error_token();
+            return self.error_token(ScanError::UnterminatedString);
         }
 
         // The closing quote.
@@ -159,11 +200,34 @@ impl Scanner {
         self.make_token(TokenType::Number)
     }
 
+    // Reports the whole character, which may be several bytes long, rather than its first byte.
+    fn unexpected_character(&mut self, source: &[u8]) -> Token {
+        let len = match source[self.start] {
+            0xF0.. => 4,
+            0xE0.. => 3,
+            0xC0.. => 2,
+            _ => 1,
+        };
+        self.current = (self.start + len).min(source.len());
+
+        let c = std::str::from_utf8(&source[self.start..self.current])
+            .ok()
+            .and_then(|chars| chars.chars().next())
+            .unwrap_or(char::REPLACEMENT_CHARACTER);
+        self.error_token(ScanError::UnexpectedCharacter(c))
+    }
+
     fn advance(&mut self, source: &[u8]) -> u8 {
         self.current += 1;
         source[self.current - 1]
     }
 
+    // Called after consuming a newline.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn is_alpha(c: u8) -> bool {
         c == b'_' || c.is_ascii_alphabetic()
     }
@@ -202,25 +266,99 @@ impl Scanner {
             token_type,
             label_start: self.start,
             label_end: self.current,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            error: None,
         }
     }
 
-    fn error_token(&self) -> Token {
+    fn error_token(&self, error: ScanError) -> Token {
         Token {
             token_type: TokenType::Error,
-            label_start: 0,
-            label_end: 0,
-            line: self.line,
+            label_start: self.start,
+            label_end: self.current,
+            line: self.start_line,
+            column: self.start_column,
+            error: Some(error),
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Token {
     pub token_type: TokenType,
     pub label_start: usize,
     pub label_end: usize,
     pub line: usize,
+    // Counted in bytes from the start of the line, starting at 1.
+    pub column: usize,
+    // Set on Error tokens, covering the source the scanner couldn't make sense of.
+    pub error: Option<ScanError>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanError {
+    UnexpectedCharacter(char),
+    UnterminatedString,
+    UnterminatedComment,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanError::UnexpectedCharacter(c) => write!(f, "Unexpected character '{}'.", c),
+            ScanError::UnterminatedString => write!(f, "Unterminated string."),
+            ScanError::UnterminatedComment => write!(f, "Unterminated block comment."),
+        }
+    }
+}
+
+impl Token {
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.label_start,
+            end: self.label_end,
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+// A range of bytes in the source, with the line and column it starts at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    // The source line the span starts on, with carets under the part of it the span covers.
+    pub fn underline(&self, source: &str) -> String {
+        let bytes = source.as_bytes();
+        let start = self.start.min(bytes.len());
+        let line_start = bytes[..start].iter().rposition(|&c| c == b'\n').map_or(0, |newline| newline + 1);
+        let line_end = bytes[start..].iter().position(|&c| c == b'\n').map_or(bytes.len(), |newline| start + newline);
+        let end = self.end.clamp(start, line_end);
+
+        // Count characters rather than bytes so the carets line up under multi-byte characters,
+        // and keep tabs so they line up under indentation.
+        let is_char_start = |c: &&u8| **c & 0b1100_0000 != 0b1000_0000;
+        let padding: String = bytes[line_start..start]
+            .iter()
+            .filter(is_char_start)
+            .map(|&c| if c == b'\t' { '\t' } else { ' ' })
+            .collect();
+        let width = bytes[start..end].iter().filter(is_char_start).count().max(1);
+
+        format!(
+            "    {}\n    {}{}",
+            String::from_utf8_lossy(&bytes[line_start..line_end]).trim_end_matches('\r'),
+            padding,
+            "^".repeat(width),
+        )
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
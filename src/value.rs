@@ -0,0 +1,37 @@
+use std::fmt;
+
+use crate::memory::{Heap, ObjRef};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn display<'a>(&self, heap: &'a Heap) -> ValueDisplay<'a> {
+        ValueDisplay { value: *self, heap }
+    }
+}
+
+pub struct ValueDisplay<'a> {
+    value: Value,
+    heap: &'a Heap,
+}
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::Obj(obj) => self.heap.fmt_object(f, obj),
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use std::{collections::HashSet, fmt};
+
+use crate::{chunk::{Chunk, OpCode}, memory::{Heap, ObjRef}, object::{Obj, ObjFunction}, value::Value};
+
+#[derive(Debug)]
+pub struct VerifyError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid bytecode at offset {}: {}", self.offset, self.message)
+    }
+}
+
+// Checks a function, and every function nested in its constants, before the VM runs it. A chunk
+// that passes only holds known opcodes with complete operands, in-bounds constants, locals and
+// upvalues, jumps to instruction boundaries, and keeps the same stack depth on every path into an
+// instruction without popping below the frame. It doesn't track the types of values on the stack,
+// so the VM still reports operands of the wrong kind as runtime errors.
+pub fn verify(heap: &Heap, function: ObjRef) -> Result<(), VerifyError> {
+    // The VM calls the script with no arguments and no captured variables.
+    if let Some(script) = heap.as_function(function) {
+        if script.arity != 0 {
+            return Err(error(0, "Top-level function takes parameters."));
+        }
+        if script.upvalue_count != 0 {
+            return Err(error(0, "Top-level function captures variables."));
+        }
+    }
+
+    let mut verified = HashSet::new();
+    verify_function(heap, function, &mut verified)
+}
+
+fn verify_function(heap: &Heap, function: ObjRef, verified: &mut HashSet<ObjRef>) -> Result<(), VerifyError> {
+    if !verified.insert(function) {
+        return Ok(());
+    }
+
+    let Some(function) = heap.as_function(function) else {
+        return Err(error(0, "Expected a function."));
+    };
+
+    let verifier = Verifier { heap, function, chunk: &function.chunk };
+    let instructions = verifier.decode()?;
+    verifier.check_stack(&instructions)?;
+
+    for constant in &function.chunk.constants {
+        if let Value::Obj(constant) = *constant {
+            if heap.as_function(constant).is_some() {
+                verify_function(heap, constant, verified)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Instruction {
+    opcode: OpCode,
+    offset: usize,
+    len: usize,
+    // Stack slots popped and pushed.
+    pops: usize,
+    pushes: usize,
+    // Set for jumps and loops.
+    target: Option<usize>,
+    // Stack slots of the locals captured by a closure.
+    captured_locals: Vec<usize>,
+}
+
+struct Verifier<'a> {
+    heap: &'a Heap,
+    function: &'a ObjFunction,
+    chunk: &'a Chunk,
+}
+
+impl Verifier<'_> {
+    // Decodes every instruction in order, checking each one's operands in isolation.
+    fn decode(&self) -> Result<Vec<Instruction>, VerifyError> {
+        if self.chunk.code.is_empty() {
+            return Err(error(0, "Chunk is empty."));
+        }
+
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < self.chunk.code.len() {
+            let instruction = self.decode_instruction(offset)?;
+            offset += instruction.len;
+            instructions.push(instruction);
+        }
+
+        for instruction in &instructions {
+            if let Some(target) = instruction.target {
+                if instructions.binary_search_by_key(&target, |instruction| instruction.offset).is_err() {
+                    return Err(error(instruction.offset, "Jump target isn't the start of an instruction."));
+                }
+            }
+        }
+
+        Ok(instructions)
+    }
+
+    fn decode_instruction(&self, offset: usize) -> Result<Instruction, VerifyError> {
+        let byte = self.chunk.code[offset];
+        let Ok(opcode) = OpCode::try_from(byte) else {
+            return Err(error(offset, &format!("Unknown opcode {}.", byte)));
+        };
+
+        let mut instruction = Instruction {
+            opcode,
+            offset,
+            len: 1,
+            pops: 0,
+            pushes: 0,
+            target: None,
+            captured_locals: Vec::new(),
+        };
+
+        match instruction.opcode {
+            OpCode::Constant | OpCode::ConstantLong => {
+                self.constant(offset, opcode)?;
+                instruction.len = 1 + constant_len(opcode);
+                instruction.pushes = 1;
+            }
+            OpCode::Nil | OpCode::True | OpCode::False => instruction.pushes = 1,
+            OpCode::Pop | OpCode::Print | OpCode::CloseUpvalue | OpCode::Return => instruction.pops = 1,
+            OpCode::GetLocal => {
+                self.operand(offset, 1)?;
+                instruction.len = 2;
+                instruction.pushes = 1;
+            }
+            OpCode::SetLocal => {
+                self.operand(offset, 1)?;
+                instruction.len = 2;
+                instruction.pops = 1;
+                instruction.pushes = 1;
+            }
+            OpCode::GetGlobal | OpCode::GetGlobalLong | OpCode::Class | OpCode::ClassLong => {
+                self.string_constant(offset, opcode)?;
+                instruction.len = 1 + constant_len(opcode);
+                instruction.pushes = 1;
+            }
+            OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                self.string_constant(offset, opcode)?;
+                instruction.len = 1 + constant_len(opcode);
+                instruction.pops = 1;
+            }
+            OpCode::SetGlobal | OpCode::SetGlobalLong | OpCode::GetProperty | OpCode::GetPropertyLong => {
+                self.string_constant(offset, opcode)?;
+                instruction.len = 1 + constant_len(opcode);
+                instruction.pops = 1;
+                instruction.pushes = 1;
+            }
+            OpCode::SetProperty
+            | OpCode::SetPropertyLong
+            | OpCode::GetSuper
+            | OpCode::GetSuperLong
+            | OpCode::Method
+            | OpCode::MethodLong => {
+                self.string_constant(offset, opcode)?;
+                instruction.len = 1 + constant_len(opcode);
+                instruction.pops = 2;
+                instruction.pushes = 1;
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                let index = self.operand(offset, 1)?;
+                if index as usize >= self.function.upvalue_count {
+                    return Err(error(offset, "Upvalue index out of range."));
+                }
+                instruction.len = 2;
+                instruction.pops = matches!(instruction.opcode, OpCode::SetUpvalue) as usize;
+                instruction.pushes = 1;
+            }
+            OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Inherit => {
+                instruction.pops = 2;
+                instruction.pushes = 1;
+            }
+            OpCode::Not | OpCode::Negate => {
+                instruction.pops = 1;
+                instruction.pushes = 1;
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                let jump = u16::from_be_bytes([self.operand(offset, 1)?, self.operand(offset, 2)?]) as usize;
+                let next = offset + 3;
+                let target = match instruction.opcode {
+                    OpCode::Loop => next.checked_sub(jump),
+                    _ => Some(next + jump),
+                };
+                instruction.target = target.filter(|&target| target < self.chunk.code.len());
+                if instruction.target.is_none() {
+                    return Err(error(offset, "Jump target out of range."));
+                }
+                instruction.len = 3;
+                if let OpCode::JumpIfFalse = instruction.opcode {
+                    instruction.pops = 1;
+                    instruction.pushes = 1;
+                }
+            }
+            OpCode::Call => {
+                let arg_count = self.operand(offset, 1)?;
+                instruction.len = 2;
+                instruction.pops = arg_count as usize + 1;
+                instruction.pushes = 1;
+            }
+            OpCode::Invoke | OpCode::InvokeLong | OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                self.string_constant(offset, opcode)?;
+                let arg_count = self.operand(offset, 1 + constant_len(opcode))?;
+                instruction.len = 2 + constant_len(opcode);
+                // The receiver, the arguments and, for super calls, the superclass.
+                instruction.pops = arg_count as usize + 1 + matches!(instruction.opcode, OpCode::SuperInvoke | OpCode::SuperInvokeLong) as usize;
+                instruction.pushes = 1;
+            }
+            OpCode::Closure | OpCode::ClosureLong => {
+                let Value::Obj(function) = self.constant(offset, opcode)? else {
+                    return Err(error(offset, "Closure constant isn't a function."));
+                };
+                let Some(function) = self.heap.as_function(function) else {
+                    return Err(error(offset, "Closure constant isn't a function."));
+                };
+
+                let upvalues = 1 + constant_len(opcode);
+                for upvalue in 0..function.upvalue_count {
+                    let is_local = self.operand(offset, upvalues + upvalue * 2)?;
+                    let index = self.operand(offset, upvalues + 1 + upvalue * 2)? as usize;
+                    match is_local {
+                        1 => instruction.captured_locals.push(index),
+                        0 if index < self.function.upvalue_count => (),
+                        0 => return Err(error(offset, "Upvalue index out of range.")),
+                        _ => return Err(error(offset, "Malformed upvalue operand.")),
+                    }
+                }
+                instruction.len = upvalues + function.upvalue_count * 2;
+                instruction.pushes = 1;
+            }
+        }
+
+        Ok(instruction)
+    }
+
+    // Walks every path through the chunk, tracking the stack depth relative to the frame.
+    fn check_stack(&self, instructions: &[Instruction]) -> Result<(), VerifyError> {
+        let index_of = |offset: usize| instructions.binary_search_by_key(&offset, |instruction| instruction.offset).unwrap();
+
+        // Slot zero holds the callee, followed by the arguments.
+        let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+        depths[0] = Some(self.function.arity + 1);
+        let mut worklist = vec![0];
+
+        while let Some(index) = worklist.pop() {
+            let instruction = &instructions[index];
+            let offset = instruction.offset;
+            let depth = depths[index].unwrap();
+
+            if instruction.pops > depth {
+                return Err(error(offset, "Stack underflow."));
+            }
+            // The return value sits above slot zero, which the VM discards along with the frame.
+            if let (OpCode::Return, ..=1) = (instruction.opcode, depth) {
+                return Err(error(offset, "Stack underflow."));
+            }
+            if let OpCode::GetLocal | OpCode::SetLocal = instruction.opcode {
+                if self.chunk.code[offset + 1] as usize >= depth {
+                    return Err(error(offset, "Local slot out of range."));
+                }
+            }
+            if instruction.captured_locals.iter().any(|&slot| slot >= depth) {
+                return Err(error(offset, "Local slot out of range."));
+            }
+
+            let depth = depth - instruction.pops + instruction.pushes;
+            let next = offset + instruction.len;
+            let successors = match instruction.opcode {
+                OpCode::Return => vec![],
+                OpCode::Jump | OpCode::Loop => vec![instruction.target.unwrap()],
+                OpCode::JumpIfFalse => vec![next, instruction.target.unwrap()],
+                _ => vec![next],
+            };
+
+            for successor in successors {
+                if successor >= self.chunk.code.len() {
+                    return Err(error(offset, "Execution runs past the end of the chunk."));
+                }
+
+                let successor = index_of(successor);
+                match depths[successor] {
+                    None => {
+                        depths[successor] = Some(depth);
+                        worklist.push(successor);
+                    }
+                    Some(existing) if existing != depth => {
+                        return Err(error(instructions[successor].offset, "Inconsistent stack depth."));
+                    }
+                    Some(_) => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn operand(&self, offset: usize, operand: usize) -> Result<u8, VerifyError> {
+        match self.chunk.code.get(offset + operand) {
+            Some(&byte) => Ok(byte),
+            None => Err(error(offset, "Operands run past the end of the chunk.")),
+        }
+    }
+
+    // The constant named by the index that follows the opcode.
+    fn constant(&self, offset: usize, opcode: OpCode) -> Result<Value, VerifyError> {
+        let index = if opcode.has_long_constant() {
+            u32::from_be_bytes([0, self.operand(offset, 1)?, self.operand(offset, 2)?, self.operand(offset, 3)?]) as usize
+        } else {
+            self.operand(offset, 1)? as usize
+        };
+        match self.chunk.constants.get(index) {
+            Some(&constant) => Ok(constant),
+            None => Err(error(offset, "Constant index out of range.")),
+        }
+    }
+
+    fn string_constant(&self, offset: usize, opcode: OpCode) -> Result<(), VerifyError> {
+        match self.constant(offset, opcode)? {
+            Value::Obj(obj) if matches!(self.heap.get(obj), Obj::String(_)) => Ok(()),
+            _ => Err(error(offset, "Expected a string constant.")),
+        }
+    }
+}
+
+// The number of bytes in the constant index of an opcode that takes one.
+fn constant_len(opcode: OpCode) -> usize {
+    if opcode.has_long_constant() { 3 } else { 1 }
+}
+
+fn error(offset: usize, message: &str) -> VerifyError {
+    VerifyError { offset, message: message.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use OpCode::*;
+
+    fn verify_code(code: &[u8], constants: &[Value]) -> Result<(), VerifyError> {
+        let mut heap = Heap::new();
+        let function = heap.new_function(ObjFunction::from_code(code, constants));
+        verify(&heap, function)
+    }
+
+    fn rejection(code: &[u8], constants: &[Value]) -> String {
+        match verify_code(code, constants) {
+            Ok(()) => panic!("{:?} should be rejected.", code),
+            Err(error) => error.message,
+        }
+    }
+
+    #[test]
+    fn return_needs_a_value_above_slot_zero() {
+        assert_eq!(rejection(&[Return as u8], &[]), "Stack underflow.");
+        assert!(verify_code(&[Nil as u8, Return as u8], &[]).is_ok());
+    }
+
+    #[test]
+    fn pops_below_the_frame_are_rejected() {
+        assert_eq!(rejection(&[Pop as u8, Pop as u8, Nil as u8, Return as u8], &[]), "Stack underflow.");
+    }
+
+    #[test]
+    fn top_level_functions_take_no_parameters_or_upvalues() {
+        let mut heap = Heap::new();
+        let mut with_parameter = ObjFunction::from_code(&[Nil as u8, Return as u8], &[]);
+        with_parameter.arity = 1;
+        let with_parameter = heap.new_function(with_parameter);
+        assert_eq!(verify(&heap, with_parameter).unwrap_err().message, "Top-level function takes parameters.");
+
+        let mut with_upvalue = ObjFunction::from_code(&[GetUpvalue as u8, 0, Return as u8], &[]);
+        with_upvalue.upvalue_count = 1;
+        let with_upvalue = heap.new_function(with_upvalue);
+        assert_eq!(verify(&heap, with_upvalue).unwrap_err().message, "Top-level function captures variables.");
+    }
+
+    #[test]
+    fn unknown_opcodes_are_rejected() {
+        assert_eq!(rejection(&[Nil as u8, 255, Return as u8], &[]), "Unknown opcode 255.");
+    }
+
+    #[test]
+    fn operands_cut_off_by_the_end_are_rejected() {
+        assert_eq!(rejection(&[Nil as u8, Jump as u8, 0], &[]), "Operands run past the end of the chunk.");
+        assert_eq!(rejection(&[Nil as u8, ConstantLong as u8, 0, 0], &[Value::Nil]), "Operands run past the end of the chunk.");
+    }
+
+    #[test]
+    fn constant_indices_out_of_range_are_rejected() {
+        assert_eq!(rejection(&[Constant as u8, 1, Return as u8], &[Value::Nil]), "Constant index out of range.");
+        assert_eq!(rejection(&[ConstantLong as u8, 0, 1, 0, Return as u8], &[Value::Nil]), "Constant index out of range.");
+    }
+
+    #[test]
+    fn jumps_into_an_instruction_are_rejected() {
+        // The jump lands on the operand of the constant.
+        let code = [Jump as u8, 0, 1, Constant as u8, 0, Return as u8];
+        assert_eq!(rejection(&code, &[Value::Nil]), "Jump target isn't the start of an instruction.");
+    }
+
+    #[test]
+    fn inconsistent_depths_at_a_join_are_rejected() {
+        // Falling through pushes one more value than taking the jump, and both paths meet at 5.
+        let code = [Nil as u8, JumpIfFalse as u8, 0, 1, Nil as u8, Nil as u8, Return as u8];
+        assert_eq!(rejection(&code, &[]), "Inconsistent stack depth.");
+    }
+}